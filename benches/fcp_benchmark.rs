@@ -34,21 +34,28 @@ fn fcp_benchmark(mut group: BenchmarkGroup<WallTime>, fixture_file: &str) {
             )
         },
     );
-    group.bench_with_input(
-        BenchmarkId::new("FCP", ""),
-        &(source, dest),
-        |b, (source, dest)| {
-            b.iter_with_setup(
-                || {
-                    remove(&dest_path);
-                    let mut command = Command::new(executable_path);
-                    command.args(&[source, dest]);
-                    command
-                },
-                run_command,
-            )
-        },
-    );
+    // When built with io_uring support, also compare against the regular filesystem backend.
+    let mut variants = vec![("FCP", None)];
+    if cfg!(feature = "io-uring") {
+        variants.push(("FCP (no io_uring)", Some("--no-io-uring")));
+    }
+    for (name, option) in variants {
+        group.bench_with_input(
+            BenchmarkId::new(name, ""),
+            &(source, dest),
+            |b, (source, dest)| {
+                b.iter_with_setup(
+                    || {
+                        remove(&dest_path);
+                        let mut command = Command::new(executable_path);
+                        command.args(option).args(&[source, dest]);
+                        command
+                    },
+                    run_command,
+                )
+            },
+        );
+    }
     group.finish();
 }
 
@@ -1,5 +1,7 @@
 use std::error;
+use std::ffi::OsStr;
 use std::fmt;
+use std::os::unix::ffi::OsStrExt;
 use std::result;
 
 #[derive(Debug)]
@@ -28,3 +30,25 @@ impl Error {
         Error(message)
     }
 }
+
+/// Displays a path (or other OS string) as-is when it is valid UTF-8. Any bytes which aren't are
+/// escaped (e.g. as `\xE9`) rather than replaced, so that the file in question can still be
+/// identified from the message.
+pub struct Escaped<'a>(&'a OsStr);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.0.as_bytes().utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            for byte in chunk.invalid() {
+                write!(f, "\\x{:02X}", byte)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Display `path` for use in a message, escaping any bytes which aren't valid UTF-8.
+pub fn escape<P: AsRef<OsStr> + ?Sized>(path: &P) -> Escaped<'_> {
+    Escaped(path.as_ref())
+}
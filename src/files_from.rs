@@ -0,0 +1,173 @@
+//! Copying the files named in a list, as given by `--files-from`. The list is read a batch of
+//! paths at a time, with each batch being copied as soon as it has been read, so that the copy
+//! can get underway before the whole list has been read (or even written, when it is piped in from
+//! another program). The paths read so far are remembered in order to reject duplicates, so memory
+//! use still grows with the length of the list, but copying never waits on the end of it.
+
+use crate::error::{escape, Error, Result};
+use crate::filesystem::{self as fs, Dir, FileType};
+use crate::limits::Semaphore;
+use crate::options::Options;
+use crate::sanitize;
+use crate::{
+    copy_file, fatal, make_dest_dir, open_or_create_dir, open_parents, relative_components, report,
+};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader};
+use std::ops::BitOr;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The maximum number of paths read from the list at a time.
+const BATCH_SIZE: usize = 128;
+
+/// The number of batches which may be in flight at once for each thread in the pool.
+const IN_FLIGHT_PER_THREAD: usize = 4;
+
+/// Copy each of the files listed in `list` (or standard input, if `list` is `-`) to the same
+/// relative path beneath the directory `dest`.
+pub fn copy_listed(list: &Path, dest: &Path, options: &Options) -> bool {
+    let mut reader: Box<dyn BufRead + Send> = if list == Path::new("-") {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(
+            fs::open(list).unwrap_or_else(|err| fatal(err)),
+        ))
+    };
+    let delimiter = if options.null { b'\0' } else { b'\n' };
+    make_dest_dir(dest, options);
+    let cwd = Dir::cwd();
+    let dest = Dir::open(dest, options.secure_dest).unwrap_or_else(|err| fatal(err));
+    let in_flight = Semaphore::new(IN_FLIGHT_PER_THREAD * rayon::current_num_threads());
+    let has_err = AtomicBool::new(false);
+    let copy_batch = |batch: Vec<PathBuf>| {
+        let batch_err = batch
+            .into_par_iter()
+            .map(|source| report(copy_entry(&cwd, &source, &dest, options)))
+            .reduce(|| false, BitOr::bitor);
+        if batch_err {
+            has_err.store(true, Ordering::Relaxed);
+        }
+    };
+    // The paths listed so far, relative to `dest` (and in the form the destination filesystem
+    // compares them in), so that paths which would be copied to the same destination as an earlier
+    // one can be rejected.
+    let mut listed = HashMap::new();
+    rayon::scope(|scope| loop {
+        let mut batch = match read_batch(&mut reader, delimiter) {
+            Ok(batch) if batch.is_empty() => break,
+            Ok(batch) => batch,
+            Err(err) => {
+                eprintln!("{}: {}", escape(list), err);
+                has_err.store(true, Ordering::Relaxed);
+                break;
+            }
+        };
+        batch.retain(|source| match relative_components(source) {
+            Ok(components) if !components.is_empty() => {
+                let dest_path = match options.case_insensitive {
+                    true => components
+                        .iter()
+                        .map(|name| sanitize::fold(name, options))
+                        .collect(),
+                    false => components.iter().collect::<PathBuf>(),
+                };
+                match listed.entry(dest_path) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(source.clone());
+                        true
+                    }
+                    Entry::Occupied(entry) => {
+                        if relative_components(entry.get()).ok() == Some(components) {
+                            eprintln!(
+                                "{}: path is listed more than once and thus would be copied to \
+                                 the same destination",
+                                escape(source)
+                            );
+                        } else {
+                            eprintln!(
+                                "{}: path would be copied to the same destination as '{}', as \
+                                 the destination filesystem doesn't distinguish their names",
+                                escape(source),
+                                escape(entry.get())
+                            );
+                        }
+                        has_err.store(true, Ordering::Relaxed);
+                        false
+                    }
+                }
+            }
+            // Errors are reported when the path is copied.
+            _ => true,
+        });
+        // As in the traversal of directories, once too many batches are in flight the batch is
+        // copied by this thread instead, which also stops reading the list from getting too far
+        // ahead of copying.
+        let copy_batch = &copy_batch;
+        match in_flight.try_acquire() {
+            Some(permit) => scope.spawn(move |_| {
+                let _permit = permit;
+                copy_batch(batch);
+            }),
+            None => copy_batch(batch),
+        }
+    });
+    has_err.into_inner()
+}
+
+/// Read up to `BATCH_SIZE` paths separated by `delimiter` from `reader`, skipping any empty ones.
+/// An empty batch means the end of the list has been reached.
+fn read_batch(reader: &mut dyn BufRead, delimiter: u8) -> io::Result<Vec<PathBuf>> {
+    let mut batch = Vec::new();
+    while batch.len() < BATCH_SIZE {
+        let mut path = Vec::new();
+        if reader.read_until(delimiter, &mut path)? == 0 {
+            break;
+        }
+        if path.last() == Some(&delimiter) {
+            path.pop();
+        }
+        if !path.is_empty() {
+            batch.push(PathBuf::from(OsString::from_vec(path)));
+        }
+    }
+    Ok(batch)
+}
+
+/// Copy the listed file `source` to the same relative path beneath `dest`. A directory is only
+/// created, as its contents are copied only if they are listed too. Paths which are nothing but
+/// the current (or root) directory are skipped, as `dest` itself stands for them.
+fn copy_entry(cwd: &Dir, source: &Path, dest: &Dir, options: &Options) -> Result<bool> {
+    let components = relative_components(source)?;
+    let (name, parents) = match components.split_last() {
+        Some(split) => split,
+        None => return Ok(false),
+    };
+    let parent = open_parents(cwd, source, parents, dest, options)?;
+    let dest_name = sanitize::dest_name(|| source.to_path_buf(), name, options);
+    let dest = parent.as_ref().unwrap_or(dest).join(&dest_name);
+    let source = cwd.join(source.as_os_str());
+    let file_type = match source.file_type() {
+        Ok(FileType::Directory) => return open_or_create_dir(source, dest).map(|_| false),
+        file_type => file_type,
+    };
+    // Copying into the directory the paths are relative to would otherwise truncate each file
+    // before it could be copied to itself.
+    if let (Ok(source_metadata), Ok(dest_metadata)) =
+        (source.symlink_metadata(), dest.symlink_metadata())
+    {
+        if (source_metadata.st_dev, source_metadata.st_ino)
+            == (dest_metadata.st_dev, dest_metadata.st_ino)
+        {
+            return Err(Error::new(format!(
+                "Cannot overwrite file '{}' with itself '{}'",
+                escape(&source.path()),
+                escape(&dest.path())
+            )));
+        }
+    }
+    Ok(copy_file(source, file_type, dest, options))
+}
@@ -2,19 +2,69 @@
 //! them to cover the full gamut of POSIX file types, and wrapping them in order to improve the
 //! usefulness of error messages by providing additional context.
 
-use crate::error::{Error, Result};
-use nix::sys::stat::Mode;
-use nix::unistd;
+use crate::error::{escape, Error, Result};
+use crate::limits::{self, Permit};
+use crate::retry::{self, OsError};
+use crate::watchdog;
+use nix::dir::{self, OwningIter};
+use nix::errno::Errno;
+use nix::fcntl::{self, AtFlags, OFlag};
+use nix::sys::stat::{self, FchmodatFlags, FileStat, Mode, SFlag};
+use nix::unistd::{self, UnlinkatFlags};
+use std::cmp;
 use std::convert::TryInto;
-use std::fs::{self, DirBuilder, DirEntry, File, Metadata, OpenOptions, Permissions, ReadDir};
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt::Display;
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions, Permissions, ReadDir};
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{self as unix, DirBuilderExt, FileTypeExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
 use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// Perform `operation` on the file at `path`, retrying it should it fail with a transient error,
+/// and reporting it should it time out.
+fn perform<T, E: OsError>(
+    path: impl Fn() -> PathBuf,
+    mut operation: impl FnMut() -> result::Result<T, E>,
+) -> result::Result<T, E> {
+    retry::retry(&path, || {
+        watchdog::watch(|| escape(&path()).to_string(), &mut operation)
+    })
+}
+
+/// As with `perform`, but for an operation creating the file at `path`, which unlike most isn't
+/// idempotent. An attempt failing with a transient error may still have created the file, in which
+/// case retrying fails because it already exists, which is then taken as success. (Binding a
+/// socket reports this with `EADDRINUSE` rather than `EEXIST`.)
+fn perform_creation<E: OsError>(
+    path: impl Fn() -> PathBuf,
+    mut operation: impl FnMut() -> result::Result<(), E>,
+) -> result::Result<(), E> {
+    let mut retrying = false;
+    perform(path, || {
+        match (mem::replace(&mut retrying, true), operation()) {
+            (true, Err(err)) if matches!(err.errno(), Some(Errno::EEXIST | Errno::EADDRINUSE)) => {
+                Ok(())
+            }
+            (_, result) => result,
+        }
+    })
+}
 
 macro_rules! wrap {
     ($namespace:ident, $function:ident, $payload:ty) => {
         pub fn $function<P: AsRef<Path>>(path: P) -> Result<$payload> {
-            $namespace::$function(path.as_ref())
-                .map_err(|err| Error::new(format!("{}: {}", path.as_ref().display(), err)))
+            let path = path.as_ref();
+            perform(|| path.to_path_buf(), || $namespace::$function(path))
+                .map_err(|err| Error::new(format!("{}: {}", escape(path), err)))
         }
     };
 }
@@ -23,9 +73,11 @@ macro_rules! wrap2 {
     ($function:ident, $namespace:ident, $payload:ty) => {
         pub fn $function<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q) -> Result<$payload> {
             let (source, dest) = (source.as_ref(), dest.as_ref());
-            $namespace::$function(source, dest).map_err(|err| {
-                Error::new(format!("{}, {}: {}", source.display(), dest.display(), err))
-            })
+            perform(
+                || source.to_path_buf(),
+                || $namespace::$function(source, dest),
+            )
+            .map_err(|err| Error::new(format!("{}, {}: {}", escape(source), escape(dest), err)))
         }
     };
 }
@@ -34,50 +86,554 @@ wrap!(fs, symlink_metadata, Metadata);
 wrap!(fs, metadata, Metadata);
 wrap!(fs, read_link, PathBuf);
 wrap!(fs, read_dir, ReadDir);
+wrap!(fs, read_to_string, String);
 wrap!(fs, remove_dir_all, ());
 wrap!(fs, remove_file, ());
 wrap!(fs, canonicalize, PathBuf);
-wrap!(fs, create_dir_all, ());
 wrap!(File, open, File);
 wrap2!(symlink, unix, ());
 wrap2!(copy, fs, u64);
 
 macro_rules! make_error_message {
     ($path:ident) => {
-        |err| Error::new(format!("{}: {}", $path.display(), err))
+        |err| Error::new(format!("{}: {}", escape(&$path), err))
     };
 }
 
-pub fn entry_file_type(entry: &DirEntry) -> Result<FileType> {
-    match entry.file_type() {
-        Err(err) => Err(Error::new(format!("{}: {}", entry.path().display(), err))),
-        Ok(file_type) => Ok(FileType::from(file_type)),
-    }
+pub fn create_dir<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+    let path = path.as_ref();
+    perform_creation(
+        || path.to_path_buf(),
+        || DirBuilder::new().mode(mode).create(path),
+    )
+    .map_err(make_error_message!(path))
 }
 
-pub fn create_dir<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+/// Create the directory `path` along with any missing ancestors, all with the given mode.
+pub fn create_dir_all<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
     let path = path.as_ref();
-    DirBuilder::new()
-        .mode(mode)
-        .create(path)
-        .map_err(make_error_message!(path))
+    perform(
+        || path.to_path_buf(),
+        || DirBuilder::new().recursive(true).mode(mode).create(path),
+    )
+    .map_err(make_error_message!(path))
 }
 
 pub fn create<P: AsRef<Path>>(path: P, mode: u32) -> Result<File> {
     let path = path.as_ref();
-    OpenOptions::new()
-        .mode(mode)
-        .truncate(true)
-        .write(true)
-        .create(true)
-        .open(path)
-        .map_err(make_error_message!(path))
+    perform(
+        || path.to_path_buf(),
+        || {
+            OpenOptions::new()
+                .mode(mode)
+                .truncate(true)
+                .write(true)
+                .create(true)
+                .open(path)
+        },
+    )
+    .map_err(make_error_message!(path))
 }
 
 pub fn mkfifo<P: AsRef<Path>>(path: P, permissions: Permissions) -> Result<()> {
     let path = path.as_ref();
+    #[allow(clippy::useless_conversion)] // `mode_t` is only 16 bits wide on some platforms
     let mode = Mode::from_bits_truncate(permissions.mode().try_into()?);
-    unistd::mkfifo(path, mode).map_err(make_error_message!(path))
+    perform_creation(|| path.to_path_buf(), || unistd::mkfifo(path, mode))
+        .map_err(make_error_message!(path))
+}
+
+/// Set once `openat2` turns out not to be supported by the kernel.
+#[cfg(target_os = "linux")]
+static OPENAT2_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// The mode of a file (including its type), widened to the type used by the standard library.
+#[allow(clippy::useless_conversion)] // `mode_t` is only 16 bits wide on some platforms
+pub fn mode(metadata: &FileStat) -> u32 {
+    u32::from(metadata.st_mode)
+}
+
+/// Open a file descriptor for the file at `path` using `open`, first waiting for one to become
+/// available, and retrying should the process run out of them regardless. The permit returned
+/// accounts for the descriptor, and must be held for as long as it remains open.
+fn open_descriptor(
+    path: impl Fn() -> PathBuf,
+    mut open: impl FnMut() -> nix::Result<RawFd>,
+) -> nix::Result<(OwnedFd, Option<Permit<'static>>)> {
+    let permit = limits::acquire_descriptor();
+    let fd = limits::retry_exhausted(|| perform(&path, &mut open))?;
+    // SAFETY: The file descriptor was just opened and is owned by nothing else.
+    Ok((unsafe { OwnedFd::from_raw_fd(fd) }, permit))
+}
+
+/// An open directory, relative to which the files within it are accessed using the `*at` family
+/// of system calls. Besides sparing the kernel from resolving the same leading path components over
+/// and over, this allows copying trees whose paths exceed `PATH_MAX`, and means that replacing an
+/// ancestor directory with a symlink partway through a copy cannot redirect it elsewhere. The path
+/// of the directory is only kept for use in error messages.
+pub struct Dir {
+    // `None` stands for the current working directory.
+    fd: Option<OwnedFd>,
+    path: PathBuf,
+    // Whether files are opened within this directory using `Location::open_beneath`.
+    secure: bool,
+    // Accounts for `fd` in the limit on the number of open file descriptors.
+    _permit: Option<Permit<'static>>,
+}
+
+impl Dir {
+    /// The current working directory, relative to which the paths given as arguments are resolved.
+    pub fn cwd() -> Dir {
+        Dir {
+            fd: None,
+            path: PathBuf::new(),
+            secure: false,
+            _permit: None,
+        }
+    }
+
+    /// Open the directory `path`. If `secure` is set then files within the directory (and any
+    /// subdirectories opened from it) are opened without ever following symlinks or leaving the
+    /// directory, whereas `path` itself is trusted and resolved as usual.
+    pub fn open(path: &Path, secure: bool) -> Result<Dir> {
+        // An empty path stands for the current directory, in keeping with the paths of the files
+        // within it being relative.
+        let resolved = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+        let (fd, permit) = open_descriptor(
+            || path.to_path_buf(),
+            || fcntl::open(resolved, flags, Mode::empty()),
+        )
+        .map_err(|err| make_error_message!(path)(io::Error::from(err)))?;
+        Ok(Dir {
+            fd: Some(fd),
+            path: path.to_path_buf(),
+            secure,
+            _permit: permit,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file named `name` within this directory.
+    pub fn join<'a>(&'a self, name: &'a OsStr) -> Location<'a> {
+        Location { dir: self, name }
+    }
+
+    /// The ID of the device the directory resides on.
+    pub fn device(&self) -> Result<u64> {
+        let path = &self.path;
+        perform(
+            || path.clone(),
+            || stat::fstatat(self.as_raw_fd(), ".", AtFlags::empty()),
+        )
+        .map(|metadata| metadata.st_dev)
+        .map_err(|err| make_error_message!(path)(io::Error::from(err)))
+    }
+
+    /// Iterate over the entries of this directory, excluding `.` and `..`.
+    /// The entries are read lazily, a buffer-full at a time, independently of this `Dir`.
+    pub fn read(&self) -> Result<Entries> {
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+        let path = &self.path;
+        let (fd, permit) = open_descriptor(
+            || path.clone(),
+            || fcntl::openat(self.as_raw_fd(), ".", flags, Mode::empty()),
+        )
+        .map_err(|err| make_error_message!(path)(io::Error::from(err)))?;
+        let dir = dir::Dir::from_fd(fd.into_raw_fd())
+            .map_err(|err| make_error_message!(path)(io::Error::from(err)))?;
+        Ok(Entries {
+            iter: dir.into_iter(),
+            path: self.path.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+impl AsRawFd for Dir {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_ref().map_or(libc::AT_FDCWD, AsRawFd::as_raw_fd)
+    }
+}
+
+/// The name, inode number, and type of a directory entry.
+pub type Entry = (OsString, u64, Result<FileType>);
+
+pub struct Entries {
+    iter: OwningIter,
+    path: PathBuf,
+    _permit: Option<Permit<'static>>,
+}
+
+impl Iterator for Entries {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.iter.next()? {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let path = &self.path;
+                    return Some(Err(make_error_message!(path)(io::Error::from(err))));
+                }
+            };
+            let name = OsStr::from_bytes(entry.file_name().to_bytes());
+            if name == "." || name == ".." {
+                continue;
+            }
+            // Not all filesystems report the type of directory entries, in which case we have to
+            // query the file itself.
+            let file_type = match entry.file_type() {
+                Some(file_type) => Ok(FileType::from(file_type)),
+                None => {
+                    let flags = AtFlags::AT_SYMLINK_NOFOLLOW;
+                    let fd = self.iter.as_raw_fd();
+                    perform(|| self.path.join(name), || stat::fstatat(fd, name, flags))
+                        .map(|metadata| FileType::from_mode(metadata.st_mode))
+                        .map_err(|err| {
+                            let path = self.path.join(name);
+                            make_error_message!(path)(io::Error::from(err))
+                        })
+                }
+            };
+            return Some(Ok((name.to_owned(), entry.ino(), file_type)));
+        }
+    }
+}
+
+/// An open file, along with the permit accounting for its file descriptor.
+pub struct OpenFile {
+    file: File,
+    _permit: Option<Permit<'static>>,
+}
+
+impl Deref for OpenFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for OpenFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+/// A file identified by its name within a directory.
+#[derive(Clone, Copy)]
+pub struct Location<'a> {
+    dir: &'a Dir,
+    name: &'a OsStr,
+}
+
+impl<'a> Location<'a> {
+    /// The path of the file, for use in error messages.
+    pub fn path(&self) -> PathBuf {
+        self.dir.path.join(self.name)
+    }
+
+    /// The directory the file is named relative to.
+    pub fn dir(&self) -> &'a Dir {
+        self.dir
+    }
+
+    /// The name of the file within `dir`, which for files given as arguments may consist of
+    /// several path components.
+    pub fn name(&self) -> &'a OsStr {
+        self.name
+    }
+
+    /// The maximum size of a regular file created here, or `None` if it is limited only by the
+    /// space available. The filesystem is identified by way of `dir` rather than the file's path.
+    pub fn max_file_size(&self) -> Option<u64> {
+        if let Some(max) = max_file_size_override() {
+            return Some(max);
+        }
+        max_file_size(self.open_parent()?.as_raw_fd())
+    }
+
+    /// Open the directory the file resides in, which for files given as arguments may lie below
+    /// `dir`. It is opened even when that is `dir` itself, as the current directory has no
+    /// descriptor of its own.
+    pub fn open_parent(&self) -> Option<OwnedFd> {
+        let parent = match Path::new(self.name).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+        let fd = fcntl::openat(self.dir.as_raw_fd(), parent, flags, Mode::empty()).ok()?;
+        // SAFETY: `fd` was just opened, and nothing else owns it.
+        Some(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// Remove the file, which mustn't be a directory.
+    pub fn remove_file(&self) -> Result<()> {
+        let name = self.name_to_create().map_err(|err| self.os_error(err))?;
+        let flags = UnlinkatFlags::NoRemoveDir;
+        self.perform(|| unistd::unlinkat(Some(self.dir.as_raw_fd()), name, flags))
+            .map_err(|err| self.os_error(err))
+    }
+
+    /// Prefix `err` with the path of the file.
+    pub fn error(&self, err: impl Display) -> Error {
+        Error::new(format!("{}: {}", escape(&self.path()), err))
+    }
+
+    fn os_error(&self, err: Errno) -> Error {
+        self.error(io::Error::from(err))
+    }
+
+    fn perform<T>(&self, operation: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+        perform(|| self.path(), operation)
+    }
+
+    fn perform_creation(&self, operation: impl FnMut() -> nix::Result<()>) -> nix::Result<()> {
+        perform_creation(|| self.path(), operation)
+    }
+
+    pub fn symlink_metadata(&self) -> Result<FileStat> {
+        let flags = AtFlags::AT_SYMLINK_NOFOLLOW;
+        self.perform(|| stat::fstatat(self.dir.as_raw_fd(), self.name, flags))
+            .map_err(|err| self.os_error(err))
+    }
+
+    pub fn file_type(&self) -> Result<FileType> {
+        Ok(FileType::from_mode(self.symlink_metadata()?.st_mode))
+    }
+
+    /// Open the file with the given flags, returning the raw error (without any added context) on
+    /// failure so that callers can handle specific errors.
+    pub fn open_with(&self, flags: OFlag, mode: u32) -> nix::Result<OpenFile> {
+        #[allow(clippy::useless_conversion)] // `mode_t` is only 16 bits wide on some platforms
+        let mode = Mode::from_bits_truncate(mode.try_into().unwrap_or_default());
+        let flags = flags | OFlag::O_CLOEXEC;
+        let (fd, permit) = open_descriptor(
+            || self.path(),
+            || {
+                if self.dir.secure {
+                    self.open_beneath(flags, mode)
+                } else {
+                    fcntl::openat(self.dir.as_raw_fd(), self.name, flags, mode)
+                }
+            },
+        )?;
+        Ok(OpenFile {
+            file: fd.into(),
+            _permit: permit,
+        })
+    }
+
+    /// Open the file such that neither a symlink nor `..` can cause a file outside of the
+    /// directory to be opened instead. Where available, `openat2` enforces this for the entire
+    /// path. Otherwise we only allow opening a single path component, which `O_NOFOLLOW` suffices
+    /// to protect.
+    fn open_beneath(&self, flags: OFlag, mode: Mode) -> nix::Result<RawFd> {
+        let flags = flags | OFlag::O_NOFOLLOW;
+        #[cfg(target_os = "linux")]
+        if !OPENAT2_UNSUPPORTED.load(Ordering::Relaxed) {
+            let name = CString::new(self.name.as_bytes()).map_err(|_| Errno::EINVAL)?;
+            // SAFETY: `open_how` consists solely of integers, for which all-zeroes is valid.
+            let mut how: libc::open_how = unsafe { mem::zeroed() };
+            how.flags = flags.bits() as u64;
+            // The mode must be zero unless a file may be created.
+            if flags.contains(OFlag::O_CREAT) {
+                how.mode = u64::from(mode.bits());
+            }
+            how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS;
+            // SAFETY: `name` and `how` remain valid for the duration of the call.
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_openat2,
+                    self.dir.as_raw_fd(),
+                    name.as_ptr(),
+                    &how as *const libc::open_how,
+                    mem::size_of::<libc::open_how>(),
+                )
+            };
+            match Errno::result(result) {
+                Ok(fd) => return Ok(fd as RawFd),
+                // `openat2` was introduced in Linux 5.6.
+                Err(Errno::ENOSYS) => OPENAT2_UNSUPPORTED.store(true, Ordering::Relaxed),
+                Err(err) => return Err(err),
+            }
+        }
+        fcntl::openat(self.dir.as_raw_fd(), self.single_component()?, flags, mode)
+    }
+
+    /// The name of the file, provided that it consists of a single path component (as is always
+    /// the case for the names of directory entries). Otherwise fails with `EXDEV`, matching the
+    /// error `openat2` gives for paths escaping the directory.
+    fn single_component(&self) -> nix::Result<&'a OsStr> {
+        let bytes = self.name.as_bytes();
+        if bytes.is_empty() || bytes.contains(&b'/') || bytes == b"." || bytes == b".." {
+            Err(Errno::EXDEV)
+        } else {
+            Ok(self.name)
+        }
+    }
+
+    /// The name to use when creating the file, which for secure directories must be a single path
+    /// component. Creating a file never follows a symlink in the final component.
+    fn name_to_create(&self) -> nix::Result<&'a OsStr> {
+        if self.dir.secure {
+            self.single_component()
+        } else {
+            Ok(self.name)
+        }
+    }
+
+    /// Open the file for reading. Symlinks are not followed, as we copy symlinks themselves rather
+    /// than the files they point to.
+    pub fn open(&self) -> Result<OpenFile> {
+        self.open_with(OFlag::O_RDONLY | OFlag::O_NOFOLLOW, 0)
+            .map_err(|err| self.os_error(err))
+    }
+
+    /// Create (or truncate) the file for writing, creating it with the given mode.
+    pub fn create(&self, mode: u32) -> Result<OpenFile> {
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC;
+        self.open_with(flags, mode)
+            .map_err(|err| self.os_error(err))
+    }
+
+    /// Open the file as a directory, failing if it is a symlink (even to a directory).
+    pub fn open_dir(&self) -> Result<Dir> {
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW;
+        let OpenFile { file, _permit } =
+            self.open_with(flags, 0).map_err(|err| self.os_error(err))?;
+        Ok(Dir {
+            fd: Some(file.into()),
+            path: self.path(),
+            secure: self.dir.secure,
+            _permit,
+        })
+    }
+
+    pub fn create_dir(&self, mode: u32) -> Result<()> {
+        #[allow(clippy::useless_conversion)]
+        let mode = Mode::from_bits_truncate(mode.try_into()?);
+        self.name_to_create()
+            .and_then(|name| {
+                self.perform_creation(|| stat::mkdirat(self.dir.as_raw_fd(), name, mode))
+            })
+            .map_err(|err| self.os_error(err))
+    }
+
+    pub fn read_link(&self) -> Result<OsString> {
+        self.perform(|| fcntl::readlinkat(self.dir.as_raw_fd(), self.name))
+            .map_err(|err| self.os_error(err))
+    }
+
+    /// Create a symlink pointing to `target`.
+    pub fn symlink(&self, target: &OsStr) -> Result<()> {
+        self.name_to_create()
+            .and_then(|name| {
+                self.perform_creation(|| {
+                    unistd::symlinkat(target, Some(self.dir.as_raw_fd()), name)
+                })
+            })
+            .map_err(|err| self.os_error(err))
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    pub fn mkfifo(&self, mode: u32) -> Result<()> {
+        #[allow(clippy::useless_conversion)]
+        let mode = Mode::from_bits_truncate(mode.try_into()?);
+        self.name_to_create()
+            .and_then(|name| {
+                self.perform_creation(|| unistd::mkfifoat(Some(self.dir.as_raw_fd()), name, mode))
+            })
+            .map_err(|err| self.os_error(err))
+    }
+
+    // Apple platforms lack `mkfifoat` (and `mknodat`), so there we have no choice but to use the
+    // full path.
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub fn mkfifo(&self, mode: u32) -> Result<()> {
+        mkfifo(self.path(), Permissions::from_mode(mode))
+    }
+
+    /// Create a Unix domain socket with the given mode, which nothing is listening on. Sockets can
+    /// only be bound to a path, so on Linux the directory is referred to by way of `/proc`, which
+    /// also keeps the path short enough to fit in a socket address.
+    pub fn mksock(&self, mode: u32) -> Result<()> {
+        #[allow(clippy::useless_conversion)]
+        let mode = Mode::from_bits_truncate(mode.try_into()?);
+        let name = self.name_to_create().map_err(|err| self.os_error(err))?;
+        let path = match &self.dir.fd {
+            #[cfg(target_os = "linux")]
+            Some(fd) => Path::new("/proc/self/fd")
+                .join(fd.as_raw_fd().to_string())
+                .join(name),
+            _ => self.path(),
+        };
+        // The socket file remains once the listener is dropped.
+        perform_creation(|| self.path(), || UnixListener::bind(&path).map(drop))
+            .map_err(|err| self.error(err))?;
+        // The mode of a socket is subject to the umask when it is bound, so it is set afterwards.
+        self.set_socket_mode(name, mode)
+    }
+
+    /// Set the mode of the socket `name` (as given by `name_to_create`), which may have been
+    /// replaced since it was created. In particular, a symlink put in its place mustn't be
+    /// followed, so that the mode of a file elsewhere can't be changed. `fchmod` doesn't accept
+    /// the `O_PATH` descriptors which can be opened for sockets, but changing the mode by way of
+    /// the descriptor's entry in `/proc` acts on the very file it refers to.
+    #[cfg(target_os = "linux")]
+    fn set_socket_mode(&self, _name: &OsStr, mode: Mode) -> Result<()> {
+        let file = self
+            .open_with(OFlag::O_PATH | OFlag::O_NOFOLLOW, 0)
+            .map_err(|err| self.os_error(err))?;
+        let metadata = self
+            .perform(|| stat::fstat(file.as_raw_fd()))
+            .map_err(|err| self.os_error(err))?;
+        if SFlag::from_bits_truncate(metadata.st_mode & SFlag::S_IFMT.bits()) != SFlag::S_IFSOCK {
+            return Err(self.error("socket was replaced by another file while being created"));
+        }
+        let path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+        let flags = FchmodatFlags::FollowSymlink;
+        self.perform(|| stat::fchmodat(None, &path, mode, flags))
+            .map_err(|err| self.os_error(err))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_socket_mode(&self, name: &OsStr, mode: Mode) -> Result<()> {
+        let flags = FchmodatFlags::NoFollowSymlink;
+        self.perform(|| stat::fchmodat(Some(self.dir.as_raw_fd()), name, mode, flags))
+            .map_err(|err| self.os_error(err))
+    }
+
+    /// Create a device node of the same type, permissions, and device number as the one described
+    /// by `metadata`.
+    pub fn mknod(&self, metadata: &FileStat) -> Result<()> {
+        let kind = SFlag::from_bits_truncate(metadata.st_mode & SFlag::S_IFMT.bits());
+        let mode = Mode::from_bits_truncate(metadata.st_mode);
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        let result = self.name_to_create().and_then(|name| {
+            self.perform_creation(|| {
+                stat::mknodat(self.dir.as_raw_fd(), name, kind, mode, metadata.st_rdev)
+            })
+        });
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let result =
+            self.perform_creation(|| stat::mknod(&self.path(), kind, mode, metadata.st_rdev));
+        result.map_err(|err| match err {
+            Errno::EPERM => self.error(
+                "creating device nodes requires the CAP_MKNOD capability \
+                 (use --copy-contents to copy the contents of devices instead)",
+            ),
+            err => self.os_error(err),
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -113,6 +669,176 @@ impl From<std::fs::FileType> for FileType {
     }
 }
 
+impl From<dir::Type> for FileType {
+    fn from(file_type: dir::Type) -> Self {
+        match file_type {
+            dir::Type::File => FileType::Regular,
+            dir::Type::Directory => FileType::Directory,
+            dir::Type::Symlink => FileType::Symlink,
+            dir::Type::Fifo => FileType::Fifo,
+            dir::Type::Socket => FileType::Socket,
+            dir::Type::CharacterDevice => FileType::CharacterDevice,
+            dir::Type::BlockDevice => FileType::BlockDevice,
+        }
+    }
+}
+
+impl FileType {
+    /// The type of a file with the given `st_mode`.
+    #[allow(clippy::useless_conversion)]
+    pub fn from_mode(mode: libc::mode_t) -> Self {
+        match SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits()) {
+            SFlag::S_IFREG => FileType::Regular,
+            SFlag::S_IFDIR => FileType::Directory,
+            SFlag::S_IFLNK => FileType::Symlink,
+            SFlag::S_IFIFO => FileType::Fifo,
+            SFlag::S_IFSOCK => FileType::Socket,
+            SFlag::S_IFCHR => FileType::CharacterDevice,
+            SFlag::S_IFBLK => FileType::BlockDevice,
+            _ => unreachable!("file appears to exist but is an unknown type",),
+        }
+    }
+}
+
+/// Whether the block device identified by `dev` (typically the `st_dev` of a file residing on it)
+/// is a rotational disk, i.e. a HDD. Devices whose type cannot be determined are assumed not to be.
+#[cfg(target_os = "linux")]
+pub fn is_rotational(dev: u64) -> bool {
+    use nix::sys::stat::{major, minor};
+    // For partitions, the `queue` directory lives in the directory of the parent device.
+    let device = PathBuf::from(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)));
+    [device.join("queue"), device.join("../queue")]
+        .iter()
+        .find_map(|queue| fs::read_to_string(queue.join("rotational")).ok())
+        .is_some_and(|rotational| rotational.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_rotational(_dev: u64) -> bool {
+    false
+}
+
+/// Whether the device `dev` (typically the `st_dev` of a file residing on it) holds a
+/// pseudo-filesystem such as procfs or sysfs, whose files report sizes unrelated to their contents
+/// and so must be read until EOF to be copied. The filesystem is identified using the directory
+/// opened by `dir` (that containing the file) the first time each device is seen.
+#[cfg(target_os = "linux")]
+pub fn is_pseudo_filesystem(dev: u64, dir: impl FnOnce() -> Option<OwnedFd>) -> bool {
+    use nix::sys::statfs::{self, *};
+    use std::sync::{PoisonError, RwLock};
+
+    // A list rather than a map suffices, as there is only ever a handful of devices involved. Once
+    // a device has been seen, every other file on it is decided under a shared lock.
+    static DEVICES: RwLock<Vec<(u64, bool)>> = RwLock::new(Vec::new());
+    let known = |devices: &[(u64, bool)]| {
+        devices
+            .iter()
+            .find(|&&(known, _)| known == dev)
+            .map(|&(_, pseudo)| pseudo)
+    };
+    if let Some(pseudo) = known(&DEVICES.read().unwrap_or_else(PoisonError::into_inner)) {
+        return pseudo;
+    }
+    // The lock isn't held across fstatfs, which may block for as long as the filesystem is
+    // unresponsive, so that only the threads copying from that filesystem wait on it. Several
+    // threads may identify the same device at once, all reaching the same answer.
+    let filesystem_type = match dir().map(|dir| statfs::fstatfs(&dir)) {
+        Some(Ok(statfs)) => statfs.filesystem_type(),
+        // Should the directory have disappeared, the next file on the device can identify it
+        // instead.
+        _ => return false,
+    };
+    let pseudo = [
+        PROC_SUPER_MAGIC,
+        SYSFS_MAGIC,
+        DEBUGFS_MAGIC,
+        TRACEFS_MAGIC,
+        SECURITYFS_MAGIC,
+        CGROUP_SUPER_MAGIC,
+        CGROUP2_SUPER_MAGIC,
+    ]
+    .contains(&filesystem_type);
+    let mut devices = DEVICES.write().unwrap_or_else(PoisonError::into_inner);
+    if known(&devices).is_none() {
+        devices.push((dev, pseudo));
+    }
+    pseudo
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_pseudo_filesystem(_dev: u64, _dir: impl FnOnce() -> Option<OwnedFd>) -> bool {
+    false
+}
+
+/// A limit on the size of files standing in for that of the destination filesystem, taken from the
+/// `FCP_MAX_FILE_SIZE` environment variable. This is for testing, as filesystems which impose a
+/// limit (such as FAT32) can't be relied upon to be available.
+pub fn max_file_size_override() -> Option<u64> {
+    static MAX_FILE_SIZE: OnceLock<Option<u64>> = OnceLock::new();
+    *MAX_FILE_SIZE.get_or_init(|| env::var("FCP_MAX_FILE_SIZE").ok()?.parse().ok())
+}
+
+/// The maximum size of a regular file in the directory `dir`, or `None` if it is limited only by
+/// the space available.
+#[cfg(target_os = "linux")]
+fn max_file_size(dir: RawFd) -> Option<u64> {
+    use nix::sys::statfs::{self, FsType, EXT2_SUPER_MAGIC, MSDOS_SUPER_MAGIC};
+
+    // This is missing from `nix`.
+    const HFS_SUPER_MAGIC: FsType = FsType(0x4244);
+    // glibc's `pathconf(_PC_FILESIZEBITS)` assumes 32 bits for any filesystem it doesn't know of
+    // (such as tmpfs), so instead we check for the filesystems known to be limited. The rest, such
+    // as exFAT, allow files as large as will fit on them.
+    let statfs = statfs::fstatfs(&dir).ok()?;
+    match statfs.filesystem_type() {
+        // FAT stores file sizes as 32-bit unsigned integers, and HFS (unlike HFS+) as signed ones.
+        MSDOS_SUPER_MAGIC => Some(u32::MAX.into()),
+        HFS_SUPER_MAGIC => Some(i32::MAX as u64),
+        // ext2 and ext3 (unlike ext4, which shares their magic number) map the blocks of a file
+        // through up to three levels of indirect blocks, each holding `block_size / 4` block
+        // numbers, which for small blocks limits files to as little as 16G.
+        EXT2_SUPER_MAGIC => {
+            let dev = stat::fstat(dir).ok()?.st_dev;
+            if !matches!(mount_type(dev)?.as_str(), "ext2" | "ext3") {
+                return None;
+            }
+            let block_size = statfs.block_size() as u64;
+            let per_block = block_size / 4;
+            let blocks = 12 + per_block + per_block.pow(2) + per_block.pow(3);
+            // The number of 512-byte sectors a file occupies is stored in 32 bits as well.
+            Some(cmp::min(blocks * block_size, 512 << 32))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn max_file_size(dir: RawFd) -> Option<u64> {
+    use nix::unistd::PathconfVar;
+    // The number of bits includes the sign bit, as `off_t` is signed.
+    match unistd::fpathconf(dir, PathconfVar::FILESIZEBITS) {
+        Ok(Some(bits @ 1..=63)) => Some((1 << (bits - 1)) - 1),
+        _ => None,
+    }
+}
+
+/// The type of the filesystem mounted from the device `dev`, as listed in `/proc/self/mountinfo`.
+#[cfg(target_os = "linux")]
+fn mount_type(dev: u64) -> Option<String> {
+    use nix::sys::stat::{major, minor};
+    let device = format!("{}:{}", major(dev), minor(dev));
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    // Each line lists the device as the third field, and the filesystem type as the first field
+    // following the ' - ' separator.
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        match mount.split(' ').nth(2) == Some(device.as_str()) {
+            true => filesystem.split(' ').next().map(str::to_owned),
+            false => None,
+        }
+    })
+}
+
 pub fn file_type(path: &Path) -> Result<FileType> {
     Ok(FileType::from(symlink_metadata(path)?.file_type()))
 }
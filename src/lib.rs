@@ -1,21 +1,36 @@
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rayon::ThreadPoolBuilder;
 use std::array;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
 use std::fmt::Display;
 use std::fs::Metadata;
-use std::io;
+use std::io::{self, Read};
+use std::mem;
 use std::ops::BitOr;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
 use std::process;
 
 pub mod error;
+mod files_from;
 pub mod filesystem;
+pub mod limits;
+pub mod options;
+pub mod retry;
+mod sanitize;
+pub mod throttle;
+mod transfer;
+mod traversal;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+pub mod watchdog;
 
-use crate::error::{Error, Result};
-use crate::filesystem::{self as fs, FileType};
+use crate::error::{escape, Error, Result};
+use crate::filesystem::{self as fs, Dir, FileType, Location};
+use crate::options::{Options, SanitizeNames, Special};
 
 pub fn fatal(message: impl Display) -> ! {
     eprintln!("{}", message);
@@ -28,106 +43,187 @@ pub fn fatal(message: impl Display) -> ! {
 // long-running jobs) as opposed to propagating it upwards and printing all errors at the end.
 // However, at the end of the process we still need to know whether or not an error occurred at any
 // point in order to set the exit code appropriately.
-fn copy_file(source: &Path, source_type: Result<FileType>, dest: &Path) -> bool {
-    fn __copy_file(source: &Path, source_type: Result<FileType>, dest: &Path) -> Result<bool> {
-        match source_type? {
-            FileType::Regular => {
-                fs::copy(source, dest)?;
+fn copy_file(
+    source: Location,
+    source_type: Result<FileType>,
+    dest: Location,
+    options: &Options,
+) -> bool {
+    fn __copy_file(
+        source: Location,
+        source_type: Result<FileType>,
+        dest: Location,
+        options: &Options,
+    ) -> Result<bool> {
+        let source_type = source_type?;
+        throttle::operation();
+        match source_type {
+            FileType::Socket
+            | FileType::Fifo
+            | FileType::CharacterDevice
+            | FileType::BlockDevice
+                if options.special == Special::Skip =>
+            {
+                if options.verbose {
+                    println!("{}: skipped special file", escape(&source.path()));
+                }
+            }
+            FileType::Regular => transfer::copy(source, dest, options)?,
+            FileType::Directory => return copy_directory(source, dest, options),
+            FileType::Symlink => dest.symlink(&source.read_link()?)?,
+            FileType::Fifo if options.copy_contents => {
+                transfer::copy_device(source, dest, false, options)?
+            }
+            FileType::Fifo => dest.mkfifo(fs::mode(&source.symlink_metadata()?))?,
+            FileType::Socket if options.special == Special::Recreate => {
+                dest.mksock(fs::mode(&source.symlink_metadata()?))?
             }
-            FileType::Directory => return copy_directory(source, dest),
-            FileType::Symlink => fs::symlink(fs::read_link(source)?, dest)?,
-            FileType::Fifo => fs::mkfifo(dest, fs::symlink_metadata(source)?.permissions())?,
             FileType::Socket => {
-                return Err(Error::new(format!(
-                    "{}: sockets cannot be copied",
-                    source.display(),
-                )));
+                return Err(source.error(
+                    "sockets cannot be copied (use --special to recreate or skip them instead)",
+                ))
             }
-            FileType::CharacterDevice | FileType::BlockDevice => {
-                let metadata = fs::symlink_metadata(source)?;
-                let mut source = fs::open(source)?;
-                let mut dest = fs::create(dest, metadata.permissions().mode())?;
-                io::copy(&mut source, &mut dest)?;
+            // As with `cp`, devices are recreated rather than having their contents copied, which
+            // for devices such as `/dev/zero` would never finish.
+            FileType::CharacterDevice | FileType::BlockDevice if !options.copy_contents => {
+                dest.mknod(&source.symlink_metadata()?)?
             }
+            FileType::CharacterDevice => transfer::copy_device(source, dest, false, options)?,
+            FileType::BlockDevice => transfer::copy_device(source, dest, true, options)?,
         }
         Ok(false)
     }
 
-    __copy_file(source, source_type, dest).unwrap_or_else(|err| {
-        eprintln!("{}", err);
-        true
-    })
+    report(__copy_file(source, source_type, dest, options))
 }
 
-fn copy_directory(source: &Path, dest: &Path) -> Result<bool> {
-    fs::create_dir(dest, fs::symlink_metadata(source)?.permissions().mode())?;
-    let (mut entries, mut has_err) = (Vec::new(), false);
-    for entry in fs::read_dir(source)? {
-        match entry {
-            Ok(entry) => entries.push((entry.file_name(), fs::entry_file_type(&entry))),
-            Err(err) => {
-                eprintln!("{}", err);
-                has_err = true;
+/// The type of `source`, a file given as an argument. With `Options::copy_contents` set, a symlink
+/// to a FIFO or device (such as `/dev/stdin` or `/proc/self/fd/N`) has the type of the file it
+/// points to, as it's the data read through it that is wanted rather than the symlink itself.
+fn argument_type(source: &Location, options: &Options) -> Result<FileType> {
+    match source.file_type()? {
+        FileType::Symlink if options.copy_contents => {
+            match std::fs::metadata(source.path()).map(|metadata| metadata.file_type()) {
+                Ok(file_type) if file_type.is_fifo() => Ok(FileType::Fifo),
+                Ok(file_type) if file_type.is_char_device() => Ok(FileType::CharacterDevice),
+                Ok(file_type) if file_type.is_block_device() => Ok(FileType::BlockDevice),
+                _ => Ok(FileType::Symlink),
             }
         }
+        file_type => Ok(file_type),
     }
-    entries.shrink_to_fit();
-    Ok(entries
-        .into_par_iter()
-        .map(|(file_name, file_type)| {
-            copy_file(&source.join(&file_name), file_type, &dest.join(&file_name))
-        })
-        .reduce(|| has_err, BitOr::bitor))
 }
 
-fn reject_self_copies(sources: &[PathBuf], dest: &Path) -> Result<()> {
-    let current_dir = env::current_dir()?;
-    let mut prefix = Path::new("");
-    // We make `dest` absolute because for relative paths the final non-`None` value returned by
-    // `Path::ancestors` is always `Some("")`, which is problematic because:
-    // 1. It would cause spurious errors, as trying to query the metadata of an empty path
-    //    trivially results in an error due to no file corresponding to the given path.
-    // 2. In some instances self-copies would not be prevented, as we wouldn't check all of our
-    //    ancestors, just the ones up to the current directory.
-    let dest = if dest.is_relative() {
-        prefix = current_dir.as_path();
-        current_dir.join(dest)
-    } else {
-        dest.to_path_buf()
+/// Copy `source`, a file given as an argument, to `dest`. As with `cp`, a device given as an
+/// argument has its contents copied even without `Options::copy_contents`, as naming it directly
+/// asks for its data; only devices found within directories are recreated.
+fn copy_argument(source: Location, dest: Location, options: &Options) -> bool {
+    let source_type = argument_type(&source, options);
+    let block = match source_type {
+        Ok(FileType::CharacterDevice) => false,
+        Ok(FileType::BlockDevice) => true,
+        _ => return copy_file(source, source_type, dest, options),
     };
-
-    // Combine device number with inode number to uniquely identify a file,
-    // since the same inode number could be used in a different filesystem.
-    fn unique_id(meta: Metadata) -> (u64, u64) {
-        (meta.dev(), meta.ino())
+    if options.copy_contents || options.special == Special::Skip {
+        return copy_file(source, source_type, dest, options);
     }
+    throttle::operation();
+    report(transfer::copy_device(source, dest, block, options).map(|()| false))
+}
 
-    // We use `fs::metadata` for `ancestor_ids` since we do the exact same thing regardless of
-    // whether `dest` is a directory or a symlink pointing to one.
-    let ancestor_ids = dest
-        .ancestors()
-        .map(|ancestor| fs::metadata(ancestor).map(unique_id));
+/// Print the error contained in `result` (if any), and return whether an error occurred.
+fn report(result: Result<bool>) -> bool {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        true
+    })
+}
 
-    // In contrast, we use `fs::symlink_metadata` for `source_ids` because we copy the symlinks
-    // themselves, not the underlying files that they point to.
-    let source_ids = sources
-        .iter()
-        .map(|source| fs::symlink_metadata(source).map(unique_id))
-        .collect::<Box<_>>();
+fn copy_directory(source: Location, dest: Location, options: &Options) -> Result<bool> {
+    dest.create_dir(fs::mode(&source.symlink_metadata()?))?;
+    traversal::copy_contents(source.open_dir()?, dest.open_dir()?, options)
+}
 
-    let mut errors = Vec::new();
+/// Uniquely identifies a file by combining its device number with its inode number, since the
+/// same inode number could be used in a different filesystem. A bind mount of a directory shares
+/// the directory's identity, so this sees through such aliases as well.
+fn unique_id(metadata: &Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Reject copying any of `sources` to the path in `dest` given by its counterpart in `dest_names`
+/// should doing so overwrite the source with itself, or copy a directory into itself (which would
+/// have the copy recurse into what it had copied so far). Either is checked for before anything is
+/// written, regardless of whether the destination exists yet. Sources lacking a destination name
+/// are only checked for being `dest` or one of its ancestors.
+fn reject_self_copies(sources: &[PathBuf], dest: &Path, dest_names: &[Vec<&OsStr>]) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    // We make `dest` absolute because for relative paths the final non-`None` value returned by
+    // `Path::ancestors` is always `Some("")`, which would stop us from checking the ancestors of
+    // the current directory.
+    let dest = current_dir.join(dest);
 
-    for (ancestor, id) in dest.ancestors().zip(ancestor_ids) {
-        let id = id?;
-        for (source, source_id) in sources.iter().zip(source_ids.as_ref()) {
-            match source_id {
-                Ok(source_id) if *source_id == id => errors.push(format!(
-                    "Cannot copy directory '{}' into itself '{}'",
-                    source.display(),
-                    ancestor.strip_prefix(prefix).unwrap_or(ancestor).display()
-                )),
-                Err(err) => errors.push(err.to_string()),
-                _ => {}
+    // Whether files exist is checked using `std::fs` directly rather than our wrappers, so that
+    // the check isn't retried when it doesn't.
+    //
+    // The destination and the directories which will contain it are identified by way of its
+    // nearest existing ancestor, which is canonicalized so that the real ancestors are checked
+    // rather than those the path happens to name (which differ when it traverses a symlink).
+    let existing = dest
+        .ancestors()
+        .find(|ancestor| std::fs::metadata(ancestor).is_ok())
+        .unwrap_or(&dest);
+    let existing = fs::canonicalize(existing)?;
+    let ancestor_ids = existing
+        .ancestors()
+        .map(|ancestor| Ok((ancestor, unique_id(&fs::metadata(ancestor)?))))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut errors = Vec::new();
+    for (index, source) in sources.iter().enumerate() {
+        // We use `fs::symlink_metadata` for sources because we copy the symlinks themselves, not
+        // the underlying files that they point to.
+        let source_id = match fs::symlink_metadata(source) {
+            Ok(metadata) => unique_id(&metadata),
+            Err(err) => {
+                errors.push(err.to_string());
+                continue;
+            }
+        };
+        let into_itself = |ancestor: &Path| {
+            format!(
+                "Cannot copy directory '{}' into itself '{}'",
+                escape(source),
+                escape(ancestor.strip_prefix(&current_dir).unwrap_or(ancestor))
+            )
+        };
+        if let Some((ancestor, _)) = ancestor_ids.iter().find(|(_, id)| *id == source_id) {
+            errors.push(into_itself(ancestor));
+            continue;
+        }
+        // Any directories between `dest` and the file copied there, as with `--parents`.
+        let mut path = dest.clone();
+        let dest_name = dest_names.get(index).map_or(&[][..], Vec::as_slice);
+        if let Some((name, parents)) = dest_name.split_last() {
+            for parent in parents {
+                path.push(parent);
+                if std::fs::metadata(&path).is_ok_and(|metadata| unique_id(&metadata) == source_id)
+                {
+                    errors.push(into_itself(&path));
+                }
+            }
+            // The destination itself may also be a symlink to the source, which would be
+            // followed when it is opened for writing.
+            path.push(name);
+            if [std::fs::symlink_metadata(&path), std::fs::metadata(&path)]
+                .iter()
+                .any(|metadata| metadata.as_ref().is_ok_and(|m| unique_id(m) == source_id))
+            {
+                errors.push(format!(
+                    "Cannot overwrite file '{}' with itself '{}'",
+                    escape(source),
+                    escape(path.strip_prefix(&current_dir).unwrap_or(&path))
+                ));
             }
         }
     }
@@ -139,86 +235,354 @@ fn reject_self_copies(sources: &[PathBuf], dest: &Path) -> Result<()> {
     }
 }
 
-fn file_names(sources: &[PathBuf]) -> Result<Vec<&OsStr>> {
-    let source_file_names = sources
+/// The paths (as lists of components) relative to the destination directory which each of
+/// `sources` is copied to. This is just the file name of each source unless `Options::parents` is
+/// set, in which case it is the source's path in its entirety. When names are sanitized, paths
+/// are compared in their sanitized form ignoring case, as the destination filesystem does.
+fn dest_names<'a>(sources: &'a [PathBuf], options: &Options) -> Result<Vec<Vec<&'a OsStr>>> {
+    let parents = options.parents;
+    let no_file_name = |source: &PathBuf| {
+        Error::new(format!(
+            "{}: path does not end with a file name",
+            escape(source)
+        ))
+    };
+    let dest_names = sources
         .iter()
-        .map(|source| {
-            source.file_name().ok_or_else(|| {
-                Error::new(format!(
-                    "{}: path does not end with a file name",
-                    source.display()
-                ))
-            })
+        .map(|source| match parents {
+            false => source
+                .file_name()
+                .map(|file_name| vec![file_name])
+                .ok_or_else(|| no_file_name(source)),
+            true => match relative_components(source)? {
+                components if components.is_empty() => Err(no_file_name(source)),
+                components => Ok(components),
+            },
         })
         .collect::<Result<Vec<_>>>()?;
     let mut sources_by_name: HashMap<_, Vec<_>> = HashMap::new();
-    for (source, file_name) in sources.iter().zip(&source_file_names) {
-        sources_by_name.entry(file_name).or_default().push(source);
+    for (source, dest_name) in sources.iter().zip(&dest_names) {
+        let dest_name = match options.case_insensitive {
+            true => dest_name
+                .iter()
+                .map(|name| sanitize::fold(name, options))
+                .collect(),
+            false => dest_name
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect::<Vec<_>>(),
+        };
+        sources_by_name.entry(dest_name).or_default().push(source);
     }
     let errors = sources_by_name
         .values()
         .filter_map(|source_group| {
-                (source_group.len() > 1).then(|| {
+            (source_group.len() > 1).then(|| {
                 format!(
-                "{}: paths have the same file name and thus would be copied to the same destination",
-                source_group
-                    .iter()
-                    .map(|source| format!("{}", source.display()))
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            )
+                    "{}: paths have the same {}{} and thus would be copied to the same destination",
+                    source_group
+                        .iter()
+                        .map(|source| format!("{}", escape(source)))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    if parents {
+                        "relative path"
+                    } else {
+                        "file name"
+                    },
+                    match options.case_insensitive {
+                        true => " on the destination filesystem",
+                        false => "",
+                    }
+                )
             })
         })
         .collect::<Vec<_>>();
     if !errors.is_empty() {
         Err(Error::new(errors.join("\n")))
     } else {
-        Ok(source_file_names)
+        Ok(dest_names)
     }
 }
 
-/// Copy each file in `sources` into the directory `dest`.
-fn copy_into(sources: &[PathBuf], dest: &Path) -> bool {
-    if let Some(err) = match fs::metadata(dest) {
-        Err(err) => Some(err),
-        Ok(metadata) if !metadata.is_dir() => {
-            Some(Error::new(format!("{} is not a directory", dest.display())))
-        }
-        _ => reject_self_copies(sources, dest).err(),
-    } {
-        fatal(err)
+/// The components of `source` to recreate beneath a destination directory, which leave out any
+/// leading `/` and `.` components.
+fn relative_components(source: &Path) -> Result<Vec<&OsStr>> {
+    source
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(Ok(name)),
+            Component::ParentDir => Some(Err(Error::new(format!(
+                "{}: paths containing '..' cannot be recreated beneath the destination",
+                escape(source)
+            )))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Open the directory `dest`, first creating it with the mode of `source` should it not exist.
+fn open_or_create_dir(source: Location, dest: Location) -> Result<Dir> {
+    if let Ok(dir) = dest.open_dir() {
+        return Ok(dir);
     }
+    let created = dest.create_dir(fs::mode(&source.symlink_metadata()?));
+    // Another thread may have created the directory in the meantime.
+    dest.open_dir().or_else(|err| created.and(Err(err)))
+}
+
+/// Open the directory beneath `dest` given by `parents`, the leading components of the relative
+/// path of `source`, creating any directories along the way which don't exist yet with the modes
+/// of their counterparts in `source`. Returns `None` if there are no `parents`, in which case the
+/// directory in question is `dest` itself. Only the names of the files copied beneath these
+/// directories are reported when sanitized, rather than those of the directories every time.
+fn open_parents(
+    cwd: &Dir,
+    source: &Path,
+    parents: &[&OsStr],
+    dest: &Dir,
+    options: &Options,
+) -> Result<Option<Dir>> {
+    let mut source_path = if source.is_absolute() {
+        PathBuf::from("/")
+    } else {
+        PathBuf::new()
+    };
+    let mut parent = None;
+    for name in parents {
+        source_path.push(name);
+        let dest_name = sanitize::sanitized(name, options);
+        let dir = open_or_create_dir(
+            cwd.join(source_path.as_os_str()),
+            parent.as_ref().unwrap_or(dest).join(&dest_name),
+        )?;
+        parent = Some(dir);
+    }
+    Ok(parent)
+}
+
+/// Create the directory `dir` along with any missing ancestors if `Options::mkdir` is set and it
+/// doesn't exist yet.
+fn make_dest_dir(dir: &Path, options: &Options) {
+    if options.mkdir && !dir.as_os_str().is_empty() && std::fs::metadata(dir).is_err() {
+        fs::create_dir_all(dir, options.mkdir_mode).unwrap_or_else(|err| fatal(err));
+    }
+}
 
+/// Copy each file in `sources` into the directory `dest`, or with `Options::parents` set to the
+/// same relative path beneath it.
+fn copy_into(sources: &[PathBuf], dest: &Path, options: &Options) -> bool {
+    let dest_names = dest_names(sources, options);
+    // Even without the destination names, copying a directory into itself can be detected.
+    let names = dest_names.as_deref().unwrap_or_default();
+    reject_self_copies(sources, dest, names).unwrap_or_else(|err| fatal(err));
+    let dest_names = dest_names.unwrap_or_else(|err| fatal(err));
+    make_dest_dir(dest, options);
+    match fs::metadata(dest) {
+        Err(err) => fatal(err),
+        Ok(metadata) if !metadata.is_dir() => fatal(format!("{} is not a directory", escape(dest))),
+        _ => {}
+    }
+
+    let cwd = Dir::cwd();
+    let dest = Dir::open(dest, options.secure_dest).unwrap_or_else(|err| fatal(err));
     sources
         .iter()
-        .zip(file_names(sources).unwrap_or_else(|err| fatal(err)))
+        .zip(dest_names)
         .collect::<Box<_>>()
         .into_par_iter()
-        .map(|(source, file_name)| copy_file(source, fs::file_type(source), &dest.join(file_name)))
+        .map(|(source, dest_name)| {
+            let (name, parents) = dest_name.split_last().expect("dest names aren't empty");
+            let parent = match open_parents(&cwd, source, parents, &dest, options) {
+                Ok(parent) => parent,
+                Err(err) => return report(Err(err)),
+            };
+            let dest_name = sanitize::dest_name(|| source.clone(), name, options);
+            let source = cwd.join(source.as_os_str());
+            let dest = parent.as_ref().unwrap_or(&dest).join(&dest_name);
+            copy_argument(source, dest, options)
+        })
         .reduce(|| false, BitOr::bitor)
 }
 
 // The `allow` here is present because clippy doesn't realize that `source` must be of
 // type `&PathBuf` in order for the call to `array::from_ref` to typecheck.
 #[allow(clippy::ptr_arg)]
-fn copy_single(source: &PathBuf, dest: &Path) -> bool {
-    let source_metadata = fs::symlink_metadata(source).unwrap_or_else(|err| fatal(err));
-    match (fs::metadata(dest), fs::symlink_metadata(dest)) {
-        (Ok(metadata), _) if metadata.is_dir() => copy_into(array::from_ref(source), dest),
-        (_, Ok(metadata)) if source_metadata.ino() == metadata.ino() => fatal(format!(
-            "Cannot overwrite file '{}' with itself '{}'",
-            source.display(),
-            dest.display()
+fn copy_single(source: &PathBuf, dest: &Path, options: &Options) -> bool {
+    fs::symlink_metadata(source).unwrap_or_else(|err| fatal(err));
+    match fs::metadata(dest) {
+        Ok(metadata) if metadata.is_dir() && !options.no_target_directory => {
+            copy_into(array::from_ref(source), dest, options)
+        }
+        _ => {
+            let (parent, dest_name) = match (dest.parent(), dest.file_name()) {
+                (Some(parent), Some(name)) => (parent, vec![name]),
+                _ => (dest, Vec::new()),
+            };
+            reject_self_copies(array::from_ref(source), parent, &[dest_name])
+                .unwrap_or_else(|err| fatal(err));
+            make_dest_dir(parent, options);
+            let dest = match dest.file_name() {
+                Some(name) => match sanitize::dest_name(|| source.clone(), name, options) {
+                    Cow::Owned(dest_name) => Cow::Owned(dest.with_file_name(dest_name)),
+                    Cow::Borrowed(_) => Cow::Borrowed(dest),
+                },
+                None => Cow::Borrowed(dest),
+            };
+            let cwd = Dir::cwd();
+            let source = cwd.join(source.as_os_str());
+            let (dest_dir, name) = dest_location(&dest, options);
+            copy_argument(source, dest_dir.join(name), options)
+        }
+    }
+}
+
+/// The directory `dest` is to be created relative to, and its name within it.
+fn dest_location<'a>(dest: &'a Path, options: &Options) -> (Dir, &'a OsStr) {
+    // In secure mode `dest` is created relative to the directory it resides in, as that is what it
+    // can't be allowed to escape from.
+    match (options.secure_dest, dest.file_name()) {
+        (false, _) => (Dir::cwd(), dest.as_os_str()),
+        (true, Some(name)) => {
+            let parent = dest.parent().unwrap_or_else(|| Path::new(""));
+            let dest_dir = Dir::open(parent, true).unwrap_or_else(|err| fatal(err));
+            (dest_dir, name)
+        }
+        (true, None) => fatal(format!(
+            "{}: path does not end with a file name",
+            escape(dest)
         )),
-        _ => copy_file(source, fs::file_type(source), dest),
     }
 }
 
-pub fn fcp(args: &[String]) -> bool {
-    let args: Box<_> = args.iter().map(PathBuf::from).collect();
-    match args.as_ref() {
-        [] | [_] => fatal("Please provide at least two arguments (run 'fcp --help' for details)"),
-        [source, dest] => copy_single(source, dest),
-        [sources @ .., dest] => copy_into(sources, dest),
+/// Copy the contents of `source` to `dest`, where a `source` of `-` stands for standard input and
+/// a `dest` of `-` for standard output. A file named `-` can still be given as `./-`.
+fn copy_standard_stream(source: &Path, dest: &Path, options: &Options) -> bool {
+    let stdio = Path::new("-");
+    let mut reader: Box<dyn Read> = if source == stdio {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(fs::open(source).unwrap_or_else(|err| fatal(err)))
+    };
+    let result = if dest == stdio {
+        transfer::copy_stream(reader, &mut io::stdout().lock(), None)
+    } else {
+        if let Some(parent) = dest.parent() {
+            make_dest_dir(parent, options);
+        }
+        let (dest_dir, name) = dest_location(dest, options);
+        let mut writer = dest_dir
+            .join(name)
+            .create(0o666)
+            .unwrap_or_else(|err| fatal(err));
+        transfer::copy_stream(&mut reader, &mut *writer, None)
+    };
+    match result {
+        Ok(_) => false,
+        Err(err) => {
+            eprintln!("{}, {}: {}", escape(source), escape(dest), err);
+            true
+        }
+    }
+}
+
+/// Copy the files given by `paths` as directed by `options`, returning whether an error occurred.
+pub fn fcp(mut options: Options, paths: Vec<PathBuf>) -> bool {
+    let retry_errors = mem::take(&mut options.retry_errors);
+    retry::configure(options.retries, retry_errors, options.verbose);
+    watchdog::configure(options.op_timeout);
+    let sources = match (&options.target_directory, paths.split_last()) {
+        (Some(_), _) => paths.as_slice(),
+        (None, Some((_, sources))) => sources,
+        (None, None) => &[],
+    };
+    // The destination is generally on a single filesystem, which decides whether names need
+    // sanitizing and how they're compared.
+    let dest = options.target_directory.as_ref().or(paths.last());
+    if options.sanitize_names == SanitizeNames::Auto {
+        options.sanitize_names = match dest {
+            Some(dest) if sanitize::is_needed(dest) => SanitizeNames::Always,
+            _ => SanitizeNames::Never,
+        };
+    }
+    options.case_insensitive |= options.sanitize_names == SanitizeNames::Always
+        || dest.is_some_and(|dest| sanitize::is_case_insensitive(dest));
+    if options.detect_hdd && !options.hdd {
+        let rotational = sources.iter().find(|source| {
+            fs::symlink_metadata(source).is_ok_and(|metadata| fs::is_rotational(metadata.dev()))
+        });
+        if let Some(source) = rotational {
+            options.hdd = true;
+            if options.verbose {
+                println!(
+                    "{}: resides on a rotational disk, enabling --hdd",
+                    escape(source)
+                );
+            }
+        }
+    }
+    if options.hdd {
+        // Splitting a file into chunks would just cause the disk head to seek between them.
+        options.chunk_size = 0;
+    }
+    limits::raise_descriptor_limit();
+    limits::set_device_limits(options.source_device_limit, options.dest_device_limit);
+    throttle::configure(
+        options.bandwidth_limit,
+        options.operations_limit,
+        options.limits_file.take(),
+    )
+    .unwrap_or_else(|err| fatal(err));
+    // The io_uring backend doesn't implement any of the options controlling how data is copied.
+    options.io_uring &= !(options.hdd
+        || options.nocache
+        || options.direct
+        || options.source_device_limit > 0
+        || options.dest_device_limit > 0
+        || options.secure_dest
+        || options.case_insensitive
+        || retry::is_enabled()
+        || watchdog::is_enabled()
+        || throttle::is_enabled());
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        options.io_uring &= uring::is_supported();
+    }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()
+        .unwrap_or_else(|err| fatal(err));
+    let has_err = watchdog::run(move || pool.install(|| copy(&options, &paths)));
+    // Operations which timed out may have gone on to complete, but were still reported as errors.
+    has_err || watchdog::timed_out()
+}
+
+/// Copy the files given by `paths` as directed by `options`, once they've been resolved.
+fn copy(options: &Options, paths: &[PathBuf]) -> bool {
+    if let Some(list) = &options.files_from {
+        return match (&options.target_directory, paths) {
+            (Some(dest), []) | (None, [dest]) => files_from::copy_listed(list, dest, options),
+            _ => fatal(
+                "Please provide only DESTINATION_DIRECTORY when using --files-from \
+                 (run 'fcp --help' for details)",
+            ),
+        };
+    }
+    match (&options.target_directory, paths) {
+        (Some(_), []) => fatal("Please provide at least one SOURCE (run 'fcp --help' for details)"),
+        (Some(dest), sources) => copy_into(sources, dest, options),
+        (None, [] | [_]) => {
+            fatal("Please provide at least two arguments (run 'fcp --help' for details)")
+        }
+        // With `--parents` the destination is always a directory, as it is with `cp`.
+        (None, [sources @ .., dest]) if options.parents => copy_into(sources, dest, options),
+        (None, [source, dest]) if source == Path::new("-") || dest == Path::new("-") => {
+            copy_standard_stream(source, dest, options)
+        }
+        (None, [source, dest]) => copy_single(source, dest, options),
+        (None, _) if options.no_target_directory => {
+            fatal("Please provide exactly two arguments when using --no-target-directory (-T)")
+        }
+        (None, [sources @ .., dest]) => copy_into(sources, dest, options),
     }
 }
@@ -0,0 +1,234 @@
+//! Limits on the number of operations which may be in flight at once, and on the number of file
+//! descriptors open at once.
+
+use nix::errno::Errno;
+use nix::sys::resource::{self, Resource};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+/// The number of file descriptors left out of those handed out by `acquire_descriptor`, for use by
+/// the standard streams, io_uring instances, and any other descriptors we don't account for.
+const RESERVED_DESCRIPTORS: usize = 64;
+
+/// How long to wait for a file descriptor to become available before going ahead regardless.
+/// Directories hold on to their descriptors while their contents are copied by other tasks, so
+/// waiting indefinitely could deadlock.
+const DESCRIPTOR_WAIT: Duration = Duration::from_secs(1);
+
+/// The longest delay between attempts to open a file while there are no descriptors available,
+/// after which we give up. The delays double each time, so this is also roughly half of the total
+/// time spent waiting.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A counting semaphore. Blocking on a semaphore from within a rayon task is only safe so long as
+/// permits are never held across calls back into rayon (e.g. `join` or parallel iterators), as
+/// otherwise the thread holding a permit could end up waiting on a task which is itself waiting
+/// for a permit.
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Returns its permit to the `Semaphore` it was acquired from once dropped.
+#[must_use]
+pub struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.permits.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait until a permit is available and take it.
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut permits = self.lock();
+        while *permits == 0 {
+            permits = self
+                .released
+                .wait(permits)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *permits -= 1;
+        Permit(self)
+    }
+
+    /// Wait until a permit is available and take it, giving up after `timeout`.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<Permit<'_>> {
+        let (mut permits, _) = self
+            .released
+            .wait_timeout_while(self.lock(), timeout, |permits| *permits == 0)
+            .unwrap_or_else(PoisonError::into_inner);
+        if *permits == 0 {
+            return None;
+        }
+        *permits -= 1;
+        Some(Permit(self))
+    }
+
+    /// Take a permit if one is available, without waiting.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut permits = self.lock();
+        if *permits == 0 {
+            return None;
+        }
+        *permits -= 1;
+        Some(Permit(self))
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.lock() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Limits the number of operations in flight per device, with each device getting its own
+/// `Semaphore` the first time it is seen.
+struct DeviceLimit {
+    limit: usize,
+    semaphores: Mutex<HashMap<u64, &'static Semaphore>>,
+}
+
+impl DeviceLimit {
+    fn acquire(&self, dev: u64) -> Permit<'static> {
+        let semaphore = *self
+            .semaphores
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(dev)
+            // Leaking is fine here, as there is only ever a handful of devices involved in a copy
+            // and the semaphores need to live for the remainder of the process anyways.
+            .or_insert_with(|| Box::leak(Box::new(Semaphore::new(self.limit))));
+        semaphore.acquire()
+    }
+}
+
+static SOURCE_DEVICE_LIMIT: OnceLock<DeviceLimit> = OnceLock::new();
+static DEST_DEVICE_LIMIT: OnceLock<DeviceLimit> = OnceLock::new();
+
+/// Set the maximum number of operations which may be in flight at once for each source and
+/// destination device. A limit of zero means no limit. Only the first call has any effect.
+pub fn set_device_limits(source_limit: usize, dest_limit: usize) {
+    for (limit, device_limit) in [
+        (source_limit, &SOURCE_DEVICE_LIMIT),
+        (dest_limit, &DEST_DEVICE_LIMIT),
+    ] {
+        if limit > 0 {
+            device_limit.get_or_init(|| DeviceLimit {
+                limit,
+                semaphores: Mutex::new(HashMap::new()),
+            });
+        }
+    }
+}
+
+/// The permits held by an operation copying from one device to another, which are released once
+/// dropped.
+#[must_use]
+pub struct DevicePermits {
+    _source: Option<Permit<'static>>,
+    _dest: Option<Permit<'static>>,
+}
+
+/// Wait until copying `source` to `dest` would not exceed the limits set by `set_device_limits`.
+/// If the device of either file cannot be determined then no limit is applied to it.
+pub fn acquire_devices(source: &File, dest: &File) -> DevicePermits {
+    // The source permit is always acquired before the destination one, which prevents deadlocks.
+    let source_permit = SOURCE_DEVICE_LIMIT.get().and_then(|limit| {
+        let metadata = source.metadata().ok()?;
+        Some(limit.acquire(metadata.dev()))
+    });
+    let dest_permit = DEST_DEVICE_LIMIT.get().and_then(|limit| {
+        let metadata = dest.metadata().ok()?;
+        Some(limit.acquire(metadata.dev()))
+    });
+    DevicePermits {
+        _source: source_permit,
+        _dest: dest_permit,
+    }
+}
+
+/// Limits the number of file descriptors open at once to (somewhat less than) `RLIMIT_NOFILE`.
+struct DescriptorLimit {
+    limit: usize,
+    semaphore: Semaphore,
+}
+
+static DESCRIPTOR_LIMIT: OnceLock<DescriptorLimit> = OnceLock::new();
+
+/// Raise the soft limit on the number of open file descriptors to the hard limit, and size the
+/// pool of descriptors handed out by `acquire_descriptor` to match. Only the first call has any
+/// effect.
+pub fn raise_descriptor_limit() {
+    DESCRIPTOR_LIMIT.get_or_init(|| {
+        let limit = match resource::getrlimit(Resource::RLIMIT_NOFILE) {
+            // On macOS raising the soft limit fails if the hard limit is unlimited, in which case
+            // we make do with the soft limit.
+            Ok((soft, hard)) => match resource::setrlimit(Resource::RLIMIT_NOFILE, hard, hard) {
+                Ok(()) => hard,
+                Err(_) => soft,
+            },
+            Err(_) => libc::RLIM_INFINITY,
+        };
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        let limit = limit - cmp::min(RESERVED_DESCRIPTORS, limit / 2);
+        DescriptorLimit {
+            limit,
+            semaphore: Semaphore::new(limit),
+        }
+    });
+}
+
+/// The number of file descriptors which may be open at once, as set by `raise_descriptor_limit`.
+pub fn descriptor_limit() -> usize {
+    DESCRIPTOR_LIMIT
+        .get()
+        .map_or(usize::MAX, |limit| limit.limit)
+}
+
+/// Wait until opening another file descriptor would not exceed the limit set by
+/// `raise_descriptor_limit`, returning a permit to be held for as long as the descriptor is open.
+/// Returns `None` if no limit has been set, or if waiting timed out.
+pub fn acquire_descriptor() -> Option<Permit<'static>> {
+    DESCRIPTOR_LIMIT
+        .get()?
+        .semaphore
+        .acquire_timeout(DESCRIPTOR_WAIT)
+}
+
+/// Take a permit as with `acquire_descriptor` if one is available, without waiting. Returns `None`
+/// if there are none available, and `Some(None)` if no limit has been set.
+pub fn try_acquire_descriptor() -> Option<Option<Permit<'static>>> {
+    match DESCRIPTOR_LIMIT.get() {
+        Some(limit) => limit.semaphore.try_acquire().map(Some),
+        None => Some(None),
+    }
+}
+
+/// Open one or more file descriptors using `open`, retrying with exponential backoff should it
+/// fail because the process (`EMFILE`) or the system as a whole (`ENFILE`) has run out of them,
+/// in the hope that others are closed in the meantime.
+pub fn retry_exhausted<T>(mut open: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    let mut delay = Duration::from_millis(1);
+    loop {
+        match open() {
+            Err(Errno::EMFILE | Errno::ENFILE) if delay <= MAX_RETRY_DELAY => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
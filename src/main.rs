@@ -1,5 +1,7 @@
+use fcp::options::Options;
 use fcp::{fatal, fcp};
 use std::env;
+use std::ffi::OsString;
 use std::process;
 
 static HELP: &str = concat!(
@@ -11,12 +13,166 @@ USAGE:
     Copy SOURCE to DESTINATION_FILE, overwriting DESTINATION_FILE if it exists
 
     fcp [OPTIONS] SOURCE ... DESTINATION_DIRECTORY
+    fcp [OPTIONS] -t DESTINATION_DIRECTORY SOURCE ...
     Copy each SOURCE into DESTINATION_DIRECTORY
 
+    fcp [OPTIONS] --files-from=FILE DESTINATION_DIRECTORY
+    Copy each file listed in FILE to the same relative path beneath DESTINATION_DIRECTORY
+
+    fcp [OPTIONS] - DESTINATION_FILE
+    fcp [OPTIONS] SOURCE -
+    Copy standard input to DESTINATION_FILE, or SOURCE to standard output
+
 OPTIONS:
+        --
+            Treat all following arguments as paths, even if they begin with '-'.
+
+        --bwlimit=SIZE
+            Copy at most SIZE bytes per second. SIZE may be suffixed with K, M, G, or T.
+
+        --case-insensitive
+            Treat the destination as comparing names ignoring case and Unicode normalization, so
+            that files whose names would collide there are detected (see --name-collisions). This
+            is enabled automatically for casefolded directories (as set by 'chattr +F') and the
+            filesystems which --sanitize-names sanitizes names for.
+
+        --chunk-size=SIZE
+            Copy regular files larger than SIZE as separate chunks of SIZE bytes in parallel.
+            SIZE may be suffixed with K, M, G, or T. A SIZE of 0 disables chunking.
+            [default: 128M]
+
+        --copy-contents
+            Copy the contents of character and block devices and FIFOs into regular files, rather
+            than recreating the files themselves (devices given directly as a SOURCE always have
+            their contents copied). A SOURCE which is a symlink to one of these, such as
+            /proc/self/fd/N, is read through as well.
+
+        --direct
+            Copy regular files and block devices using direct I/O (O_DIRECT), bypassing the page
+            cache. Files on filesystems which don't support direct I/O are copied normally.
+            (Linux only)
+
+        --dest-device-limit=N
+            Allow at most N files to be written to any one destination device at once.
+
+        --files-from=FILE
+            Copy the files listed in FILE, one per line, rather than SOURCEs given as arguments.
+            Use '-' to read the list from standard input. Missing directories leading up to each
+            file are created, while directories which are listed are created without copying
+            their contents, which are only copied if listed too.
+
+        --hdd
+            Optimize for copying from a HDD by reading files sequentially in on-disk order,
+            which disables chunking and io_uring. This is enabled automatically when a SOURCE
+            resides on a rotational disk (as reported by --verbose), unless --no-hdd is given.
+
     -h, --help
             Output this usage information and exit.
 
+        --iops-limit=N
+            Copy at most N files per second.
+
+    -j, --jobs=N
+            Copy using N threads. Defaults to the value of the FCP_JOBS environment variable
+            if set, or otherwise the number of CPUs.
+
+        --limits-file=FILE
+            Read the bandwidth and file limits from FILE, which consists of lines of the form
+            'bwlimit=SIZE' and 'iops-limit=N'. FILE is re-read whenever it is modified, allowing
+            the limits to be adjusted while a copy is in progress.
+
+    -D, --mkdir
+            Create DESTINATION_DIRECTORY (or the directory containing DESTINATION_FILE) along
+            with any missing ancestors, should it not exist.
+
+        --mkdir-mode=MODE
+            The mode, in octal, of the directories created by --mkdir, less the umask.
+            [default: 777]
+
+        --name-collisions=POLICY
+            What happens to files whose names collide in a case-insensitive destination, where
+            POLICY is one of:
+              error   Report them as errors, leaving them uncopied. [default]
+              rename  Copy them under a name such as 'README (1)', reporting each one renamed.
+            SOURCEs given as arguments whose names collide are always reported as errors.
+
+        --nocache
+            Evict copied data from the page cache as the copy progresses, rather than evicting
+            other data which is more likely to be used again. (Linux only)
+
+        --no-hdd
+            Don't enable --hdd automatically, such as for a virtual disk which reports itself as
+            rotational despite not being one.
+
+        --no-io-uring
+            Don't use io_uring, even if it is supported. This is only relevant if fcp was built
+            with the 'io-uring' feature, which uses io_uring to speed up copying many small files.
+
+    -T, --no-target-directory
+            Treat DESTINATION_FILE as a file, even if it is an existing directory.
+
+    -0, --null
+            Separate the files listed in the --files-from FILE with NUL characters rather than
+            newlines, as output by 'find -print0' and 'git ls-files -z'.
+
+        --op-timeout=DURATION
+            Report any operation taking longer than DURATION as an error, and carry on copying
+            everything else without waiting for it. DURATION is in seconds unless suffixed with
+            ms, s, m, or h.
+
+        --parents
+            Copy each SOURCE to its full path beneath DESTINATION_DIRECTORY rather than just its
+            file name, creating any missing directories along the way with the modes of their
+            counterparts in the SOURCE path (e.g. 'fcp --parents a/b/c d' copies to 'd/a/b/c').
+
+        --retries=N
+            Retry operations failing with transient errors (see --retry-errors) up to N times,
+            waiting twice as long before each successive retry. [default: 0]
+
+        --retry-errors=ERRORS
+            The comma-separated names of the errors for which operations are retried.
+            [default: EIO,ESTALE,EAGAIN,EINTR]
+
+        --sanitize-names=WHEN
+            Rewrite file names which the destination filesystem can't store, where WHEN is one of:
+              auto    Sanitize names when copying onto a FAT, exFAT, or SMB filesystem. [default]
+              always  Sanitize names regardless of the destination filesystem.
+              never   Copy names as they are.
+            Characters such as ':' and '?' (along with trailing dots and spaces) are replaced with
+            '_', and reserved names such as 'CON' have '_' inserted before any extension (so
+            'CON.txt' becomes 'CON_.txt'). Each renamed file is reported, and files whose names
+            then collide are handled as set by --name-collisions.
+
+        --secure-dest
+            Never follow symlinks within the destination, and never create files outside of it.
+            Use this when copying into a directory which untrusted users can write to.
+
+        --source-device-limit=N
+            Allow at most N files to be read from any one source device at once.
+
+        --special=MODE
+            How special files are copied, where MODE is one of:
+              error     Recreate FIFOs and devices, and report sockets as errors. [default]
+              skip      Leave sockets, FIFOs, and devices out of the copy, along with files in
+                        /proc and /sys which can't be read.
+              recreate  Recreate sockets (which nothing listens on) as well as FIFOs and devices.
+            Recreating devices requires the CAP_MKNOD capability (see --copy-contents).
+
+        --split=SIZE
+            Copy regular files too large for the destination filesystem (such as those over 4G on
+            FAT32) as parts of SIZE bytes named FILE.000, FILE.001, and so on, along with a
+            FILE.manifest listing them. Otherwise such files are reported as errors before
+            anything is written. SIZE may be suffixed with K, M, G, or T.
+
+    -t, --target-directory=DIRECTORY
+            Copy each SOURCE into DIRECTORY. This is useful when the SOURCEs come last, as with
+            'xargs' or 'find -exec ... +'.
+
+    -v, --verbose
+            Report operations which succeeded only after being retried, special files skipped
+            by --special=skip, files copied in parts by --split, and --hdd being enabled
+            automatically.
+
     -V, --version
             Output version information and exit."
 );
@@ -24,13 +180,13 @@ OPTIONS:
 static VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
-    let args: Box<[String]> = env::args().skip(1).collect();
-    for arg in args.iter() {
-        match arg.as_str() {
-            "-h" | "--help" => fatal(HELP),
-            "-V" | "--version" => fatal(VERSION),
-            _ => {}
-        }
+    let args: Box<[OsString]> = env::args_os().skip(1).collect();
+    let (options, paths) = Options::parse(&args).unwrap_or_else(|err| fatal(err));
+    if options.help {
+        fatal(HELP);
+    }
+    if options.version {
+        fatal(VERSION);
     }
-    process::exit(fcp(&args) as i32);
+    process::exit(fcp(options, paths) as i32);
 }
@@ -0,0 +1,481 @@
+//! Parsing of the command-line options which tune how `fcp` performs a copy.
+
+use crate::error::{escape, Error, Result};
+use crate::retry;
+use nix::errno::Errno;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::str;
+use std::time::Duration;
+
+/// How special files (sockets, FIFOs, and devices) are copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Special {
+    /// FIFOs and devices are recreated, whereas sockets are reported as errors.
+    Error,
+    /// Special files are left out of the copy.
+    Skip,
+    /// All special files are recreated, including sockets.
+    Recreate,
+}
+
+/// When file names are rewritten into a form which the destination filesystem can store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SanitizeNames {
+    /// Names are sanitized if the destination is on a FAT, exFAT, or SMB filesystem.
+    Auto,
+    /// Names are always sanitized.
+    Always,
+    /// Names are copied as they are.
+    Never,
+}
+
+/// What happens to files whose names would collide with those of other files in the destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameCollisions {
+    /// Colliding files are reported as errors, and not copied.
+    Error,
+    /// Colliding files are copied under a new name which doesn't collide.
+    Rename,
+}
+
+/// Options controlling the behavior of a copy.
+#[derive(Debug)]
+pub struct Options {
+    /// Regular files larger than this many bytes are split into chunks of this size which are
+    /// copied in parallel. A value of zero disables chunking.
+    pub chunk_size: u64,
+    /// Copy the contents of character and block devices into regular files, rather than
+    /// recreating the devices themselves.
+    pub copy_contents: bool,
+    /// Optimize for sources residing on a HDD by reading files sequentially in (approximately)
+    /// the order they are laid out on disk, rather than all at once.
+    pub hdd: bool,
+    /// Enable `hdd` automatically when a source resides on a rotational disk.
+    pub detect_hdd: bool,
+    /// The number of threads used to perform the copy, or zero to use one per CPU.
+    pub jobs: usize,
+    /// The maximum number of operations reading from any one device at once, or zero for no limit.
+    pub source_device_limit: usize,
+    /// The maximum number of operations writing to any one device at once, or zero for no limit.
+    pub dest_device_limit: usize,
+    /// The maximum number of bytes copied per second, or zero for no limit.
+    pub bandwidth_limit: u64,
+    /// The maximum number of files copied per second, or zero for no limit.
+    pub operations_limit: u64,
+    /// A file from which to read the bandwidth and operations limits, which is re-read whenever it
+    /// is modified.
+    pub limits_file: Option<PathBuf>,
+    /// Evict copied data from the page cache as the copy progresses.
+    pub nocache: bool,
+    /// Copy regular files and block devices using direct I/O, bypassing the page cache.
+    pub direct: bool,
+    /// Never follow symlinks when creating or opening files in the destination, nor allow any file
+    /// to be created outside of it. This protects against other users planting symlinks in a
+    /// destination they can write to.
+    pub secure_dest: bool,
+    /// Use io_uring to batch together system calls where supported. This is only available when
+    /// `fcp` is built with the `io-uring` feature.
+    pub io_uring: bool,
+    /// The number of times an operation failing with one of `retry_errors` is retried.
+    pub retries: usize,
+    /// The errors considered transient, and thus worth retrying.
+    pub retry_errors: Vec<Errno>,
+    /// How special files are copied.
+    pub special: Special,
+    /// Regular files too large for the destination filesystem are copied as parts of this many
+    /// bytes, rather than being reported as errors. A value of zero disables splitting.
+    pub split: u64,
+    /// When file names are rewritten to be legal on the destination filesystem.
+    pub sanitize_names: SanitizeNames,
+    /// The destination compares names ignoring case and Unicode normalization, so that names which
+    /// differ only in these ways collide.
+    pub case_insensitive: bool,
+    /// What happens to files whose names collide in a case-insensitive destination.
+    pub name_collisions: NameCollisions,
+    /// Report operations which only succeeded after being retried, and special files skipped.
+    pub verbose: bool,
+    /// Operations taking longer than this are reported and abandoned. A value of zero means no
+    /// limit.
+    pub op_timeout: Duration,
+    /// Copy all of the paths given into this directory.
+    pub target_directory: Option<PathBuf>,
+    /// Treat the last path given as the destination file, even if it is a directory.
+    pub no_target_directory: bool,
+    /// Copy the paths listed in this file (or standard input, if `-`) rather than those given as
+    /// arguments, recreating them beneath the destination directory.
+    pub files_from: Option<PathBuf>,
+    /// The paths listed in `files_from` are separated by NUL characters rather than newlines.
+    pub null: bool,
+    /// Create the destination directory (or the directory containing the destination file) along
+    /// with any missing ancestors, should it not exist.
+    pub mkdir: bool,
+    /// The mode with which directories are created by `mkdir`, less the umask.
+    pub mkdir_mode: u32,
+    /// Copy each path given to the same relative path beneath the destination directory, rather
+    /// than just its file name.
+    pub parents: bool,
+    /// Output usage information instead of copying anything.
+    pub help: bool,
+    /// Output version information instead of copying anything.
+    pub version: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            chunk_size: 128 << 20,
+            copy_contents: false,
+            hdd: false,
+            detect_hdd: true,
+            jobs: 0,
+            source_device_limit: 0,
+            dest_device_limit: 0,
+            bandwidth_limit: 0,
+            operations_limit: 0,
+            limits_file: None,
+            nocache: false,
+            direct: false,
+            secure_dest: false,
+            io_uring: cfg!(feature = "io-uring"),
+            retries: 0,
+            retry_errors: retry::DEFAULT_ERRORS.to_vec(),
+            special: Special::Error,
+            split: 0,
+            sanitize_names: SanitizeNames::Auto,
+            case_insensitive: false,
+            name_collisions: NameCollisions::Error,
+            verbose: false,
+            op_timeout: Duration::ZERO,
+            target_directory: None,
+            no_target_directory: false,
+            files_from: None,
+            null: false,
+            mkdir: false,
+            mkdir_mode: 0o777,
+            parents: false,
+            help: false,
+            version: false,
+        }
+    }
+}
+
+/// The options which take a value, by their long names.
+const VALUE_OPTIONS: &[&str] = &[
+    "bwlimit",
+    "chunk-size",
+    "dest-device-limit",
+    "files-from",
+    "iops-limit",
+    "jobs",
+    "limits-file",
+    "mkdir-mode",
+    "name-collisions",
+    "op-timeout",
+    "retries",
+    "retry-errors",
+    "sanitize-names",
+    "source-device-limit",
+    "special",
+    "split",
+    "target-directory",
+];
+
+/// The options which don't take a value, by their long names.
+const FLAG_OPTIONS: &[&str] = &[
+    "case-insensitive",
+    "copy-contents",
+    "direct",
+    "hdd",
+    "help",
+    "mkdir",
+    "no-hdd",
+    "no-io-uring",
+    "no-target-directory",
+    "nocache",
+    "null",
+    "parents",
+    "secure-dest",
+    "verbose",
+    "version",
+];
+
+/// The short forms of options, along with their long names.
+const SHORT_OPTIONS: &[(char, &str)] = &[
+    ('h', "help"),
+    ('j', "jobs"),
+    ('D', "mkdir"),
+    ('T', "no-target-directory"),
+    ('0', "null"),
+    ('t', "target-directory"),
+    ('v', "verbose"),
+    ('V', "version"),
+];
+
+fn unknown_option(option: &OsStr) -> Error {
+    Error::new(format!(
+        "{}: unknown option (run 'fcp --help' for details)",
+        escape(option)
+    ))
+}
+
+impl Options {
+    /// Separate the options in `args` from the paths to be copied. Options may also be provided
+    /// via the environment, though those given as arguments take precedence. Arguments following
+    /// `--` are always treated as paths.
+    pub fn parse(args: &[OsString]) -> Result<(Options, Vec<PathBuf>)> {
+        let mut options = Options::default();
+        if let Ok(jobs) = env::var("FCP_JOBS") {
+            options.jobs =
+                parse_count(&jobs).map_err(|err| Error::new(format!("FCP_JOBS={}", err)))?;
+        }
+        let mut paths = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |option: &OsStr| {
+                args.next()
+                    .map(OsString::as_os_str)
+                    .ok_or_else(|| Error::new(format!("{}: missing value", escape(option))))
+            };
+            // Arguments are handled as bytes, as paths (and hence the values of options taking a
+            // path) needn't be valid UTF-8.
+            let bytes = arg.as_bytes();
+            if bytes == b"--" {
+                paths.extend(args.map(PathBuf::from));
+                break;
+            } else if let Some(long) = bytes.strip_prefix(b"--") {
+                let (name, inline_value) = match long.iter().position(|&byte| byte == b'=') {
+                    Some(index) => (&long[..index], Some(OsStr::from_bytes(&long[index + 1..]))),
+                    None => (long, None),
+                };
+                let name = str::from_utf8(name).map_err(|_| unknown_option(arg))?;
+                if FLAG_OPTIONS.contains(&name) {
+                    if inline_value.is_some() {
+                        return Err(Error::new(format!(
+                            "--{}: option does not take a value",
+                            name
+                        )));
+                    }
+                    options.set_flag(name);
+                } else if VALUE_OPTIONS.contains(&name) {
+                    let value = match inline_value {
+                        Some(value) => value,
+                        None => value(arg)?,
+                    };
+                    options.set(name, value)?;
+                } else {
+                    return Err(unknown_option(arg));
+                }
+            } else if let Some(flags) = bytes.strip_prefix(b"-").filter(|flags| !flags.is_empty()) {
+                // Short options may be combined (e.g. `-vT`), with an option taking a value
+                // consuming the remainder of the argument (e.g. `-j4`) or else the next one.
+                for (index, &flag) in flags.iter().enumerate() {
+                    let name = SHORT_OPTIONS
+                        .iter()
+                        .find(|(short, _)| u32::from(*short) == u32::from(flag))
+                        .map(|(_, name)| *name);
+                    let name = match name {
+                        Some(name) => name,
+                        // Short options are all ASCII, so anything else (which may be a multi-byte
+                        // character) is reported along with the remainder of the argument.
+                        None if flag.is_ascii() => {
+                            return Err(unknown_option(OsStr::from_bytes(&[b'-', flag])))
+                        }
+                        None => {
+                            let option = [b"-", &flags[index..]].concat();
+                            return Err(unknown_option(OsStr::from_bytes(&option)));
+                        }
+                    };
+                    if VALUE_OPTIONS.contains(&name) {
+                        let value = match &flags[index + 1..] {
+                            [] => value(OsStr::from_bytes(&[b'-', flag]))?,
+                            rest => OsStr::from_bytes(rest),
+                        };
+                        options.set(name, value)?;
+                        break;
+                    }
+                    options.set_flag(name);
+                }
+            } else {
+                paths.push(PathBuf::from(arg));
+            }
+        }
+        if options.target_directory.is_some() && options.no_target_directory {
+            return Err(Error::new(
+                "cannot combine --target-directory (-t) and --no-target-directory (-T)".into(),
+            ));
+        }
+        if options.parents && options.no_target_directory {
+            return Err(Error::new(
+                "cannot combine --parents and --no-target-directory (-T)".into(),
+            ));
+        }
+        Ok((options, paths))
+    }
+
+    /// Set the option named `name`, which doesn't take a value.
+    fn set_flag(&mut self, name: &str) {
+        match name {
+            "case-insensitive" => self.case_insensitive = true,
+            "copy-contents" => self.copy_contents = true,
+            "direct" => self.direct = true,
+            "hdd" => self.hdd = true,
+            "help" => self.help = true,
+            "mkdir" => self.mkdir = true,
+            "no-hdd" => {
+                self.hdd = false;
+                self.detect_hdd = false;
+            }
+            "no-io-uring" => self.io_uring = false,
+            "no-target-directory" => self.no_target_directory = true,
+            "nocache" => self.nocache = true,
+            "null" => self.null = true,
+            "parents" => self.parents = true,
+            "secure-dest" => self.secure_dest = true,
+            "verbose" => self.verbose = true,
+            "version" => self.version = true,
+            _ => unreachable!("unhandled option --{}", name),
+        }
+    }
+
+    /// Set the option named `name` to `value`. Only the values of options taking a path may be
+    /// anything other than valid UTF-8.
+    fn set(&mut self, name: &str, value: &OsStr) -> Result<()> {
+        let text = || {
+            value
+                .to_str()
+                .ok_or_else(|| Error::new(format!("{}: invalid value", escape(value))))
+        };
+        match name {
+            "bwlimit" => self.bandwidth_limit = parse_size(text()?)?,
+            "chunk-size" => self.chunk_size = parse_size(text()?)?,
+            "dest-device-limit" => self.dest_device_limit = parse_count(text()?)?,
+            "files-from" => self.files_from = Some(value.into()),
+            "iops-limit" => self.operations_limit = parse_count(text()?)? as u64,
+            "jobs" => self.jobs = parse_count(text()?)?,
+            "limits-file" => self.limits_file = Some(value.into()),
+            "mkdir-mode" => self.mkdir_mode = parse_mode(text()?)?,
+            "name-collisions" => self.name_collisions = parse_name_collisions(text()?)?,
+            "op-timeout" => self.op_timeout = parse_duration(text()?)?,
+            "retries" => self.retries = parse_count(text()?)?,
+            "retry-errors" => self.retry_errors = parse_errors(text()?)?,
+            "sanitize-names" => self.sanitize_names = parse_sanitize_names(text()?)?,
+            "source-device-limit" => self.source_device_limit = parse_count(text()?)?,
+            "special" => self.special = parse_special(text()?)?,
+            "split" => self.split = parse_size(text()?)?,
+            "target-directory" => self.target_directory = Some(value.into()),
+            _ => unreachable!("unhandled option --{}", name),
+        }
+        Ok(())
+    }
+}
+
+/// Parse a non-negative integer, such as a number of threads.
+pub fn parse_count(count: &str) -> Result<usize> {
+    count
+        .parse()
+        .map_err(|_| Error::new(format!("{}: invalid number", count)))
+}
+
+/// Parse a duration, consisting of a number optionally followed by one of the units `ms`, `s`,
+/// `m`, or `h`. A number without a unit is in seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let invalid = || Error::new(format!("{}: invalid duration", duration));
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let seconds = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(invalid()),
+    };
+    let value: f64 = value.parse().map_err(|_| invalid())?;
+    Duration::try_from_secs_f64(value * seconds).map_err(|_| invalid())
+}
+
+/// Parse a comma-separated list of error names, such as `EIO,ESTALE`.
+fn parse_errors(errors: &str) -> Result<Vec<Errno>> {
+    errors
+        .split(',')
+        .map(|name| {
+            // `Errno` has no way of looking up an error by name, but its `Debug` representation
+            // is the name, so we search through the possible values instead.
+            (1..4096)
+                .map(Errno::from_i32)
+                .filter(|errno| *errno != Errno::UnknownErrno)
+                .find(|errno| format!("{:?}", errno) == name)
+                .ok_or_else(|| Error::new(format!("{}: unknown error name", name)))
+        })
+        .collect()
+}
+
+/// Parse a file mode given in octal, such as `755`.
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| Error::new(format!("{}: invalid mode", mode)))
+}
+
+/// Parse what happens to files whose names collide, which is one of `error` or `rename`.
+fn parse_name_collisions(name_collisions: &str) -> Result<NameCollisions> {
+    match name_collisions {
+        "error" => Ok(NameCollisions::Error),
+        "rename" => Ok(NameCollisions::Rename),
+        _ => Err(Error::new(format!(
+            "{}: expected one of error or rename",
+            name_collisions
+        ))),
+    }
+}
+
+/// Parse when file names are to be sanitized, which is one of `auto`, `always`, or `never`.
+fn parse_sanitize_names(sanitize_names: &str) -> Result<SanitizeNames> {
+    match sanitize_names {
+        "auto" => Ok(SanitizeNames::Auto),
+        "always" => Ok(SanitizeNames::Always),
+        "never" => Ok(SanitizeNames::Never),
+        _ => Err(Error::new(format!(
+            "{}: expected one of auto, always, or never",
+            sanitize_names
+        ))),
+    }
+}
+
+/// Parse how special files are to be copied, which is one of `error`, `skip`, or `recreate`.
+fn parse_special(special: &str) -> Result<Special> {
+    match special {
+        "error" => Ok(Special::Error),
+        "skip" => Ok(Special::Skip),
+        "recreate" => Ok(Special::Recreate),
+        _ => Err(Error::new(format!(
+            "{}: expected one of error, skip, or recreate",
+            special
+        ))),
+    }
+}
+
+/// Parse a size in bytes, optionally followed by one of the binary suffixes `K`, `M`, `G`, or `T`.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let invalid = || Error::new(format!("{}: invalid size", size));
+    let (digits, shift) = match size.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let shift = match suffix.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(invalid()),
+            };
+            (&size[..index], shift)
+        }
+        _ => (size, 0),
+    };
+    let value: u64 = digits.parse().map_err(|_| invalid())?;
+    value.checked_mul(1 << shift).ok_or_else(invalid)
+}
@@ -0,0 +1,102 @@
+//! Retrying of filesystem operations which fail with errors that are likely to be transient, such
+//! as those which network and FUSE filesystems sporadically report.
+
+use crate::error::escape;
+use crate::watchdog;
+use nix::errno::Errno;
+use std::cmp;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// The delay before the first retry of an operation, which doubles with each subsequent retry.
+const INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// The longest delay between retries of an operation.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// The errors which are retried unless configured otherwise.
+pub const DEFAULT_ERRORS: [Errno; 4] = [Errno::EIO, Errno::ESTALE, Errno::EAGAIN, Errno::EINTR];
+
+struct Policy {
+    retries: usize,
+    errors: Vec<Errno>,
+    verbose: bool,
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Retry operations failing with any of `errors` up to `retries` times. If `verbose` is set then
+/// operations which succeed only after being retried are reported. Only the first call has any
+/// effect.
+pub fn configure(retries: usize, errors: Vec<Errno>, verbose: bool) {
+    if retries > 0 {
+        POLICY.get_or_init(|| Policy {
+            retries,
+            errors,
+            verbose,
+        });
+    }
+}
+
+/// Whether operations are retried at all.
+pub fn is_enabled() -> bool {
+    POLICY.get().is_some()
+}
+
+/// An error which may have been caused by a system call failing.
+pub trait OsError {
+    fn errno(&self) -> Option<Errno>;
+}
+
+impl OsError for Errno {
+    fn errno(&self) -> Option<Errno> {
+        Some(*self)
+    }
+}
+
+impl OsError for io::Error {
+    fn errno(&self) -> Option<Errno> {
+        self.raw_os_error().map(Errno::from_i32)
+    }
+}
+
+/// Perform `operation` on the file at `path`, retrying it with exponential backoff for as long as
+/// it fails with a retryable error and retries remain.
+pub fn retry<T, E: OsError>(
+    path: impl Fn() -> PathBuf,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let policy = match POLICY.get() {
+        Some(policy) => policy,
+        None => return operation(),
+    };
+    let (mut retries, mut delay, mut last_error) = (0, INITIAL_DELAY, None);
+    loop {
+        match operation() {
+            Err(err) if retries < policy.retries => {
+                match err.errno().filter(|errno| policy.errors.contains(errno)) {
+                    Some(errno) => last_error = Some(errno),
+                    None => return Err(err),
+                }
+            }
+            Ok(value) => {
+                if let (true, Some(last_error)) = (policy.verbose, last_error) {
+                    println!(
+                        "{}: succeeded after {} {} (last error: {})",
+                        escape(&path()),
+                        retries,
+                        if retries == 1 { "retry" } else { "retries" },
+                        io::Error::from(last_error),
+                    );
+                }
+                return Ok(value);
+            }
+            result => return result,
+        }
+        watchdog::sleep(delay);
+        delay = cmp::min(delay * 2, MAX_DELAY);
+        retries += 1;
+    }
+}
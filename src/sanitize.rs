@@ -0,0 +1,231 @@
+//! Rewriting file names into a form which can be stored on FAT, exFAT, and SMB filesystems (as
+//! used by USB sticks and Windows shares), which disallow certain characters and names. These
+//! filesystems (like casefolded ext4 directories) also compare names ignoring case and Unicode
+//! normalization, so names which would end up the same there are detected rather than being
+//! copied over one another.
+
+use crate::error::{escape, Result};
+use crate::filesystem::Dir;
+use crate::options::{NameCollisions, Options, SanitizeNames};
+use caseless::Caseless;
+use std::borrow::Cow;
+use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// The characters which can't appear in names, besides control characters.
+const ILLEGAL_CHARACTERS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// The names reserved for devices, which can't be used even with an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "AUX", "CON", "NUL", "PRN", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The character substituted for those which can't be stored.
+const REPLACEMENT: char = '_';
+
+/// `dest` itself if it exists, or otherwise its nearest ancestor which does.
+fn nearest_existing(dest: &Path) -> &Path {
+    dest.ancestors()
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| fs::symlink_metadata(dir).is_ok())
+        .unwrap_or(dest)
+}
+
+/// Whether names must be sanitized to be copied into `dest`, i.e. whether it (or its nearest
+/// existing ancestor, should it not exist yet) resides on a FAT, exFAT, or SMB filesystem.
+#[cfg(target_os = "linux")]
+pub fn is_needed(dest: &Path) -> bool {
+    use nix::sys::statfs::{self, FsType, MSDOS_SUPER_MAGIC, SMB_SUPER_MAGIC};
+
+    // These are missing from `nix`.
+    const EXFAT_SUPER_MAGIC: FsType = FsType(0x2011_bab0_u32 as _);
+    const CIFS_SUPER_MAGIC: FsType = FsType(0xff53_4d42_u32 as _);
+    const SMB2_SUPER_MAGIC: FsType = FsType(0xfe53_4d42_u32 as _);
+    statfs::statfs(nearest_existing(dest)).is_ok_and(|statfs| {
+        [
+            MSDOS_SUPER_MAGIC,
+            EXFAT_SUPER_MAGIC,
+            SMB_SUPER_MAGIC,
+            CIFS_SUPER_MAGIC,
+            SMB2_SUPER_MAGIC,
+        ]
+        .contains(&statfs.filesystem_type())
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_needed(_dest: &Path) -> bool {
+    false
+}
+
+/// Whether `dest` (or its nearest existing ancestor) compares names ignoring case and Unicode
+/// normalization, as casefolded ext4 directories and the filesystems names are sanitized for do.
+pub fn is_case_insensitive(dest: &Path) -> bool {
+    is_needed(dest) || is_casefolded(nearest_existing(dest))
+}
+
+/// Whether the directory `dir` has the casefold attribute, as set by `chattr +F`.
+#[cfg(target_os = "linux")]
+fn is_casefolded(dir: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    const FS_CASEFOLD_FL: libc::c_long = 0x4000_0000;
+    let dir = match File::open(dir) {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    let mut flags: libc::c_long = 0;
+    // SAFETY: `FS_IOC_GETFLAGS` writes the flags to the integer pointed to, which outlives the
+    // call.
+    let result = unsafe { libc::ioctl(dir.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    result == 0 && flags & FS_CASEFOLD_FL != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_casefolded(_dir: &Path) -> bool {
+    false
+}
+
+/// The name `name` is stored under. Illegal characters (and bytes which aren't valid UTF-8) are
+/// replaced with underscores, as are trailing dots and spaces, and reserved names have an
+/// underscore inserted before any extension.
+pub fn sanitize(name: &OsStr) -> Cow<'_, OsStr> {
+    let mut sanitized = String::with_capacity(name.len());
+    for chunk in name.as_bytes().utf8_chunks() {
+        sanitized.extend(chunk.valid().chars().map(|c| {
+            if c.is_control() || ILLEGAL_CHARACTERS.contains(&c) {
+                REPLACEMENT
+            } else {
+                c
+            }
+        }));
+        sanitized.extend(chunk.invalid().iter().map(|_| REPLACEMENT));
+    }
+    // Windows silently drops trailing dots and spaces, so they can't be stored either.
+    let trailing = sanitized.len() - sanitized.trim_end_matches(['.', ' ']).len();
+    sanitized.truncate(sanitized.len() - trailing);
+    sanitized.extend((0..trailing).map(|_| REPLACEMENT));
+    let stem = sanitized.find('.').unwrap_or(sanitized.len());
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(&sanitized[..stem]))
+    {
+        sanitized.insert(stem, REPLACEMENT);
+    }
+    if sanitized.as_bytes() == name.as_bytes() {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(sanitized.into())
+    }
+}
+
+/// The form in which the destination compares `name` once it's been sanitized (should
+/// `Options::sanitize_names` call for it), which ignores case and Unicode normalization.
+pub fn fold(name: &OsStr, options: &Options) -> OsString {
+    compared(&sanitized(name, options))
+}
+
+/// The form in which the destination compares the name `dest_name`, i.e. its canonical caseless
+/// form as defined by the Unicode Standard (full case folding of its canonical decomposition), as
+/// casefolded ext4 directories use.
+fn compared(dest_name: &OsStr) -> OsString {
+    match dest_name.to_str() {
+        Some(name) => name
+            .nfd()
+            .default_case_fold()
+            .nfd()
+            .collect::<String>()
+            .into(),
+        // Names which aren't valid UTF-8 can't be folded, and so are compared exactly.
+        None => dest_name.to_os_string(),
+    }
+}
+
+/// `name` with ` (n)` inserted before any extension, e.g. `notes (1).txt`.
+fn numbered(name: &OsStr, n: usize) -> OsString {
+    let bytes = name.as_bytes();
+    let stem = match bytes.iter().rposition(|&byte| byte == b'.') {
+        Some(0) | None => bytes.len(),
+        Some(index) => index,
+    };
+    let mut numbered = bytes[..stem].to_vec();
+    numbered.extend_from_slice(format!(" ({})", n).as_bytes());
+    numbered.extend_from_slice(&bytes[stem..]);
+    OsString::from_vec(numbered)
+}
+
+/// The name `name` is to be copied to, which is sanitized if `Options::sanitize_names` calls for
+/// it.
+pub fn sanitized<'a>(name: &'a OsStr, options: &Options) -> Cow<'a, OsStr> {
+    match options.sanitize_names {
+        SanitizeNames::Always => sanitize(name),
+        _ => Cow::Borrowed(name),
+    }
+}
+
+/// As with `sanitized`, but reporting the renaming of the file `source` (should it be renamed).
+pub fn dest_name<'a>(
+    source: impl FnOnce() -> PathBuf,
+    name: &'a OsStr,
+    options: &Options,
+) -> Cow<'a, OsStr> {
+    let dest_name = sanitized(name, options);
+    report_rename(source, name, &dest_name);
+    dest_name
+}
+
+/// Report the renaming of the file `source` named `name` to `dest_name`, should they differ.
+fn report_rename(source: impl FnOnce() -> PathBuf, name: &OsStr, dest_name: &OsStr) {
+    if dest_name != name {
+        println!("{}: renamed to '{}'", escape(&source()), escape(dest_name));
+    }
+}
+
+/// The names claimed by the entries of a directory being copied into a case-insensitive
+/// destination (in the form in which they're compared), so that entries whose names would collide
+/// there are detected.
+#[derive(Default)]
+pub struct Claimed(HashMap<OsString, OsString>);
+
+impl Claimed {
+    /// Claim the name for the entry `name` of the directory `source`, returning the name it's to
+    /// be copied to. Should an earlier entry have claimed the same name this fails, unless
+    /// `Options::name_collisions` calls for the entry to be renamed instead.
+    pub fn claim<'a>(
+        &mut self,
+        source: &Dir,
+        name: &'a OsStr,
+        options: &Options,
+    ) -> Result<Cow<'a, OsStr>> {
+        let sanitized = sanitized(name, options);
+        let mut dest_name = sanitized.clone();
+        for n in 1.. {
+            match self.0.entry(compared(&dest_name)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(name.to_os_string());
+                    break;
+                }
+                Entry::Occupied(_) if options.name_collisions == NameCollisions::Rename => {
+                    dest_name = Cow::Owned(numbered(&sanitized, n));
+                }
+                Entry::Occupied(entry) => {
+                    return Err(source.join(name).error(format_args!(
+                        "would be copied to the same destination as '{}', as the destination \
+                         filesystem doesn't distinguish their names",
+                        escape(entry.get())
+                    )))
+                }
+            }
+        }
+        report_rename(|| source.join(name).path(), name, &dest_name);
+        Ok(dest_name)
+    }
+}
@@ -0,0 +1,212 @@
+//! Rate limiting of the bandwidth and number of file operations used by a copy, shared across all
+//! threads. The limits can optionally be read from a control file, which is re-read whenever it is
+//! modified so that the limits can be adjusted while a copy is in progress.
+
+use crate::error::{escape, Error, Result};
+use crate::filesystem as fs;
+use crate::options::{parse_count, parse_size};
+use crate::watchdog;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The number of bytes copied between checks of the bandwidth limit.
+pub const BLOCK_SIZE: u64 = 256 << 10;
+
+/// How often the control file is checked for modifications.
+const CONTROL_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket which refills at `rate` tokens per second, holding at most one second's worth.
+/// Callers may take more tokens than are available, in which case they are made to wait until
+/// the resulting debt has been paid off.
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    /// Take `amount` tokens, returning how long the caller must wait before proceeding.
+    fn take(&mut self, amount: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = self.rate as f64;
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - amount as f64;
+        self.last_refill = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+struct ControlFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+struct Throttle {
+    bandwidth: Bucket,
+    operations: Bucket,
+    control_file: Option<ControlFile>,
+}
+
+impl Throttle {
+    fn reload_if_modified(&mut self, now: Instant) {
+        let control_file = match &mut self.control_file {
+            Some(control_file) if now - control_file.last_checked >= CONTROL_FILE_INTERVAL => {
+                control_file
+            }
+            _ => return,
+        };
+        control_file.last_checked = now;
+        let modified = fs::metadata(&control_file.path)
+            .and_then(|metadata| Ok(metadata.modified()?))
+            .ok();
+        if modified == control_file.modified {
+            return;
+        }
+        control_file.modified = modified;
+        // If the control file is malformed or temporarily missing (e.g. because it is in the
+        // middle of being replaced) we keep using the current limits.
+        match read_control_file(&control_file.path) {
+            Ok((bandwidth, operations)) => {
+                self.bandwidth.set_rate(bandwidth);
+                self.operations.set_rate(operations);
+            }
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BANDWIDTH_LIMITED: AtomicBool = AtomicBool::new(false);
+static THROTTLE: Mutex<Option<Throttle>> = Mutex::new(None);
+
+/// Limit copying to at most `bandwidth` bytes and `operations` files per second, where a limit of
+/// zero means no limit. If `control_file` is given then the limits are instead read from it, and
+/// re-read whenever it is modified. The control file consists of lines of the form `bwlimit=SIZE`
+/// and `iops-limit=N`, with blank lines and lines starting with `#` ignored.
+pub fn configure(bandwidth: u64, operations: u64, control_file: Option<PathBuf>) -> Result<()> {
+    let (bandwidth, operations, control_file) = match control_file {
+        None if bandwidth == 0 && operations == 0 => return Ok(()),
+        None => (bandwidth, operations, None),
+        Some(path) => {
+            let modified = fs::metadata(&path)?.modified()?;
+            let (bandwidth, operations) = read_control_file(&path)?;
+            let control_file = ControlFile {
+                path,
+                modified: Some(modified),
+                last_checked: Instant::now(),
+            };
+            (bandwidth, operations, Some(control_file))
+        }
+    };
+    let control_file_given = control_file.is_some();
+    *THROTTLE.lock().unwrap_or_else(PoisonError::into_inner) = Some(Throttle {
+        bandwidth: Bucket::new(bandwidth),
+        operations: Bucket::new(operations),
+        control_file,
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    BANDWIDTH_LIMITED.store(bandwidth > 0 || control_file_given, Ordering::Relaxed);
+    Ok(())
+}
+
+fn read_control_file(path: &Path) -> Result<(u64, u64)> {
+    let contents = fs::read_to_string(path)?;
+    let (mut bandwidth, mut operations) = (0, 0);
+    for line in contents.lines().map(str::trim) {
+        let error = || Error::new(format!("{}: invalid line '{}'", escape(path), line));
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+        {
+            Some(("bwlimit", value)) => bandwidth = parse_size(value).map_err(|_| error())?,
+            Some(("iops-limit", value)) => {
+                operations = parse_count(value).map_err(|_| error())? as u64
+            }
+            _ => return Err(error()),
+        }
+    }
+    Ok((bandwidth, operations))
+}
+
+/// Whether any limits are (or may later be) in effect.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether the bandwidth is (or may later be) limited, in which case data must be copied a block
+/// at a time so that the limit can be checked in between. The operations limit alone only needs
+/// checking once per file.
+pub fn limits_bandwidth() -> bool {
+    BANDWIDTH_LIMITED.load(Ordering::Relaxed)
+}
+
+fn take(amount: u64, bucket: fn(&mut Throttle) -> &mut Bucket) {
+    if !is_enabled() {
+        return;
+    }
+    let delay = {
+        let mut throttle = THROTTLE.lock().unwrap_or_else(PoisonError::into_inner);
+        let throttle = match throttle.as_mut() {
+            Some(throttle) => throttle,
+            None => return,
+        };
+        let now = Instant::now();
+        throttle.reload_if_modified(now);
+        bucket(throttle).take(amount, now)
+    };
+    if !delay.is_zero() {
+        watchdog::sleep(delay);
+    }
+}
+
+/// Wait until `amount` more bytes may be copied without exceeding the bandwidth limit.
+pub fn bytes(amount: u64) {
+    if !limits_bandwidth() {
+        return;
+    }
+    take(amount, |throttle| &mut throttle.bandwidth)
+}
+
+/// Wait until another file may be copied without exceeding the operations limit.
+pub fn operation() {
+    take(1, |throttle| &mut throttle.operations)
+}
+
+/// A reader whose reads are subject to the bandwidth limit.
+pub struct Throttled<R>(pub R);
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buffer)?;
+        bytes(read as u64);
+        Ok(read)
+    }
+}
@@ -0,0 +1,634 @@
+//! Copying the contents of regular files, devices, and other streams of data, choosing the fastest
+//! strategy available for a given file.
+
+use crate::error::{escape, Error, Result};
+use crate::filesystem::{self as fs, FileType, Location, OpenFile};
+use crate::limits;
+use crate::options::{Options, Special};
+use crate::throttle::{self, Throttled};
+use crate::watchdog::{self, Operation};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use std::cmp;
+use std::ffi::OsStr;
+use std::fs::{File, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+
+use nix::fcntl::OFlag;
+
+#[cfg(target_os = "linux")]
+use {
+    nix::errno::Errno,
+    nix::fcntl::{self, FallocateFlags, FcntlArg, PosixFadviseAdvice},
+    std::os::unix::io::AsRawFd,
+};
+
+/// The number of bytes copied at a time when using `--nocache`, after which the corresponding
+/// pages are evicted from the page cache.
+const NOCACHE_BLOCK_SIZE: u64 = 8 << 20;
+
+/// The number of bytes copied at a time when using `--op-timeout`, after which the timeout is
+/// restarted.
+const WATCHED_BLOCK_SIZE: u64 = 8 << 20;
+
+/// The size of the buffer used when copying streams of data, such as the contents of devices and
+/// pipes. This is larger than what `io::copy` uses by default, so that fewer system calls are made.
+const STREAM_BUFFER_SIZE: usize = 1 << 20;
+
+/// The alignment required of buffers, offsets, and lengths when performing direct I/O. This is the
+/// page size on most systems, which in turn is a multiple of the logical block size of virtually
+/// all storage devices.
+#[cfg(target_os = "linux")]
+const DIRECT_ALIGNMENT: u64 = 4096;
+
+/// The size of the buffer used when performing direct I/O.
+#[cfg(target_os = "linux")]
+const DIRECT_BUFFER_SIZE: u64 = 1 << 20;
+
+/// How the ranges of a file are to be copied.
+#[derive(Clone, Copy)]
+struct Strategy<'a> {
+    /// The maximum number of bytes to copy between checks of the bandwidth limit and evictions
+    /// from the page cache.
+    block_size: u64,
+    /// Whether the files were opened with `O_DIRECT`.
+    direct: bool,
+    /// Whether to evict the copied data from the page cache.
+    nocache: bool,
+    /// The watched operation to report progress to after each block.
+    operation: Option<&'a Operation>,
+}
+
+/// A reader which reports progress to the watched operation (if any) after each read, so that a
+/// stream which keeps on delivering data isn't taken to have hung.
+struct Watched<'a, R>(R, Option<&'a Operation>);
+
+impl<R: Read> Read for Watched<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buffer)?;
+        if let Some(operation) = self.1 {
+            operation.progress();
+        }
+        Ok(read)
+    }
+}
+
+/// Start watching the copy of `source` to `dest`.
+fn watch(source: &Location, dest: &Location) -> Option<Operation> {
+    watchdog::start(|| format!("{}, {}", escape(&source.path()), escape(&dest.path())))
+}
+
+impl Strategy<'_> {
+    /// Account for a block of `len` bytes about to be copied.
+    fn block(&self, len: u64) {
+        throttle::bytes(len);
+        if let Some(operation) = self.operation {
+            operation.progress();
+        }
+    }
+}
+
+/// Copy the regular file `source` to `dest`, including its permissions.
+pub fn copy(source: Location, dest: Location, options: &Options) -> Result<()> {
+    // Splitting files into chunks is only worthwhile where we have `copy_file_range`, as elsewhere
+    // the kernel has no way of copying multiple ranges of a file concurrently.
+    let chunked = cfg!(target_os = "linux") && options.chunk_size > 0;
+    // A plain `io::copy` copies the whole file in one go, so we can't use it if we need control
+    // over how the data is copied.
+    let manual =
+        throttle::limits_bandwidth() || watchdog::is_enabled() || options.nocache || options.direct;
+    let metadata = source.symlink_metadata()?;
+    let (len, mode) = (metadata.st_size as u64, fs::mode(&metadata));
+    if fs::is_pseudo_filesystem(metadata.st_dev, || source.open_parent()) {
+        return copy_pseudo_file(source, dest, mode, options);
+    }
+    // Checking up front avoids writing most of a file only to fail with `EFBIG`. Files this small
+    // fit on any filesystem, so the check is only made for those which might not.
+    if len > i32::MAX as u64 || fs::max_file_size_override().is_some() {
+        match dest.max_file_size() {
+            Some(max) if len > max && options.split == 0 => {
+                return Err(source.error(format!(
+                    "file is too large for the destination filesystem, which allows at most {} \
+                     bytes per file (use --split to copy it in parts instead)",
+                    max
+                )))
+            }
+            Some(max) if len > max && options.split > max => {
+                return Err(source.error(format!(
+                    "the size given by --split exceeds the {} bytes per file which the \
+                     destination filesystem allows",
+                    max
+                )))
+            }
+            Some(max) if len > max => return copy_split(source, dest, len, mode, options),
+            _ => {}
+        }
+    }
+    let chunk_size = if chunked && len > options.chunk_size {
+        options.chunk_size
+    } else {
+        u64::MAX
+    };
+    if chunk_size != u64::MAX || manual {
+        return copy_ranges(source, dest, len, mode, chunk_size, options);
+    }
+    let mut source_file = source.open()?;
+    let mut dest_file = dest.create(mode)?;
+    let _permits = limits::acquire_devices(&source_file, &dest_file);
+    // Much like `fs::copy`, this makes use of `copy_file_range` on Linux.
+    io::copy(&mut *source_file, &mut *dest_file).map_err(|err| {
+        Error::new(format!(
+            "{}, {}: {}",
+            escape(&source.path()),
+            escape(&dest.path()),
+            err
+        ))
+    })?;
+    set_permissions(&dest_file, mode).map_err(|err| dest.error(err))
+}
+
+/// Copy the regular file `source` of `len` bytes, which is too large for the destination
+/// filesystem, as parts of `Options::split` bytes named after `dest` with the suffixes `.000`,
+/// `.001`, and so on. These are listed (along with their sizes) in a manifest named after `dest`
+/// with the suffix `.manifest`, as the file can be reassembled by concatenating them in order. The
+/// names in the manifest are written byte for byte, each at the end of its own line. Should any
+/// part fail to be copied, those already written are removed.
+fn copy_split(
+    source: Location,
+    dest: Location,
+    len: u64,
+    mode: u32,
+    options: &Options,
+) -> Result<()> {
+    let source_file = source.open()?;
+    let part_size = options.split;
+    let parts = len.div_ceil(part_size);
+    // The suffixes are padded to the same width, so that the parts sort in order.
+    let width = cmp::max(3, (parts - 1).to_string().len());
+    let suffixed = |name: &OsStr, suffix: &str| {
+        let mut name = name.to_os_string();
+        name.push(suffix);
+        name
+    };
+    let dest_path = dest.path();
+    let file_name = dest_path.file_name().unwrap_or_default();
+    let mut manifest = b"# The parts of '".to_vec();
+    manifest.extend_from_slice(file_name.as_bytes());
+    manifest.extend_from_slice(
+        format!(
+            "' ({} bytes), to be concatenated in order to reassemble it.\n",
+            len
+        )
+        .as_bytes(),
+    );
+    // The names of the files written so far, to be removed should the copy fail.
+    let mut written = Vec::new();
+    let operation = watch(&source, &dest);
+    let mut write_parts = || -> Result<()> {
+        for part in 0..parts {
+            let suffix = format!(".{:0width$}", part, width = width);
+            let part_len = cmp::min(part_size, len - part * part_size);
+            let part_name = suffixed(dest.name(), &suffix);
+            let part_dest = dest.dir().join(&part_name);
+            let mut part_file = part_dest.create(mode)?;
+            written.push(part_name.clone());
+            let _permits = limits::acquire_devices(&source_file, &part_file);
+            let part_source = (&*source_file).take(part_len);
+            copy_stream(part_source, &mut *part_file, operation.as_ref()).map_err(|err| {
+                Error::new(format!(
+                    "{}, {}: {}",
+                    escape(&source.path()),
+                    escape(&part_dest.path()),
+                    err
+                ))
+            })?;
+            set_permissions(&part_file, mode).map_err(|err| part_dest.error(err))?;
+            manifest.extend_from_slice(format!("{} ", part_len).as_bytes());
+            manifest.extend_from_slice(suffixed(file_name, &suffix).as_bytes());
+            manifest.push(b'\n');
+        }
+        let manifest_name = suffixed(dest.name(), ".manifest");
+        let manifest_dest = dest.dir().join(&manifest_name);
+        let mut manifest_file = manifest_dest.create(0o666)?;
+        written.push(manifest_name.clone());
+        manifest_file
+            .write_all(&manifest)
+            .map_err(|err| manifest_dest.error(err))
+    };
+    if let Err(err) = write_parts() {
+        let mut message = err.to_string();
+        for name in &written {
+            if let Err(err) = dest.dir().join(name).remove_file() {
+                message.push_str(&format!("\n{}", err));
+            }
+        }
+        return Err(Error::new(message));
+    }
+    if options.verbose {
+        println!("{}: split into {} parts", escape(&source.path()), parts);
+    }
+    Ok(())
+}
+
+/// Copy the regular file `source` residing on a pseudo-filesystem such as procfs or sysfs by
+/// reading it until EOF, as its reported size can't be trusted. Attributes which can't be read
+/// (failing with `EACCES` or `EIO`, as many in sysfs do) are skipped when `--special=skip` is set.
+fn copy_pseudo_file(source: Location, dest: Location, mode: u32, options: &Options) -> Result<()> {
+    let source_file = match source.open_with(OFlag::O_RDONLY | OFlag::O_NOFOLLOW, 0) {
+        Ok(file) => file,
+        Err(err) => return skip_unreadable(&source, io::Error::from(err), options),
+    };
+    // Unreadable attributes fail on the first read, which is made before creating `dest` so that
+    // nothing is left behind for those which are skipped.
+    let operation = watch(&source, &dest);
+    let source_file = Watched(&*source_file, operation.as_ref());
+    let mut reader = BufReader::with_capacity(STREAM_BUFFER_SIZE, Throttled(source_file));
+    if let Err(err) = reader.fill_buf() {
+        return skip_unreadable(&source, err, options);
+    }
+    let mut dest_file = dest.create(mode)?;
+    io::copy(&mut reader, &mut *dest_file).map_err(|err| {
+        Error::new(format!(
+            "{}, {}: {}",
+            escape(&source.path()),
+            escape(&dest.path()),
+            err
+        ))
+    })?;
+    set_permissions(&dest_file, mode).map_err(|err| dest.error(err))
+}
+
+/// Report `err`, which occurred reading the pseudo-file `source`, unless it is one of the errors
+/// which `--special=skip` skips such files for.
+fn skip_unreadable(source: &Location, err: io::Error, options: &Options) -> Result<()> {
+    match err.raw_os_error() {
+        Some(libc::EACCES | libc::EIO) if options.special == Special::Skip => {
+            if options.verbose {
+                println!(
+                    "{}: skipped unreadable file ({})",
+                    escape(&source.path()),
+                    err
+                );
+            }
+            Ok(())
+        }
+        _ => Err(source.error(err)),
+    }
+}
+
+/// Copy the contents of the character or block device (or FIFO) `source` into the regular file
+/// `dest`. The source may also be a symlink to one, in which case it is read through the symlink.
+pub fn copy_device(
+    source: Location,
+    dest: Location,
+    block_device: bool,
+    options: &Options,
+) -> Result<()> {
+    let metadata = source.symlink_metadata()?;
+    let symlink = matches!(FileType::from_mode(metadata.st_mode), FileType::Symlink);
+    if block_device && !symlink && (options.nocache || options.direct) {
+        let len = source
+            .open()?
+            .seek(SeekFrom::End(0))
+            .map_err(|err| source.error(err))?;
+        return copy_ranges(source, dest, len, fs::mode(&metadata), u64::MAX, options);
+    }
+    let mut source_file = source
+        .open_with(OFlag::O_RDONLY, 0)
+        .map_err(|err| source.error(io::Error::from(err)))?;
+    let mode = source_file
+        .metadata()
+        .map_err(|err| source.error(err))?
+        .permissions()
+        .mode();
+    let mut dest_file = dest.create(mode)?;
+    let _permits = limits::acquire_devices(&source_file, &dest_file);
+    let operation = watch(&source, &dest);
+    copy_stream(&mut *source_file, &mut *dest_file, operation.as_ref())?;
+    Ok(())
+}
+
+/// Copy everything read from `source` into `dest`, subject to the bandwidth limit, reporting
+/// progress to `operation` after each read.
+pub fn copy_stream(
+    source: impl Read,
+    dest: &mut impl Write,
+    operation: Option<&Operation>,
+) -> io::Result<u64> {
+    // `io::copy` copies using the buffer of a `BufReader` when given one.
+    let source = Watched(source, operation);
+    let mut source = BufReader::with_capacity(STREAM_BUFFER_SIZE, Throttled(source));
+    io::copy(&mut source, dest)
+}
+
+/// Set the permissions of `file` to those given by `mode`. The mode given when creating a file is
+/// subject to the umask, so we explicitly set the permissions afterwards in order to match the
+/// behavior of `fs::copy`.
+fn set_permissions(file: &File, mode: u32) -> io::Result<()> {
+    file.set_permissions(Permissions::from_mode(mode))
+}
+
+/// Copy the first `len` bytes of `source` to `dest` by splitting them into ranges of `chunk_size`
+/// bytes, each of which is copied as a separate task on the current rayon thread-pool. This
+/// allows a single very large file to make use of the full parallelism of the underlying storage,
+/// which a single sequential stream of `copy_file_range` calls is unable to do.
+fn copy_ranges(
+    source: Location,
+    dest: Location,
+    len: u64,
+    mode: u32,
+    chunk_size: u64,
+    options: &Options,
+) -> Result<()> {
+    let error_message = |err| {
+        Error::new(format!(
+            "{}, {}: {}",
+            escape(&source.path()),
+            escape(&dest.path()),
+            err
+        ))
+    };
+    let dest_error_message = |err| dest.error(err);
+    let (source_file, dest_file, direct) = open(source, dest, mode, options)?;
+    let operation = watch(&source, &dest);
+    let strategy = Strategy {
+        block_size: if throttle::limits_bandwidth() {
+            throttle::BLOCK_SIZE
+        } else if options.nocache {
+            NOCACHE_BLOCK_SIZE
+        } else if operation.is_some() {
+            WATCHED_BLOCK_SIZE
+        } else {
+            u64::MAX
+        },
+        direct,
+        nocache: options.nocache,
+        operation: operation.as_ref(),
+    };
+    #[cfg(target_os = "linux")]
+    let chunk_size = if direct {
+        // Each chunk must begin at an aligned offset.
+        chunk_size
+            .checked_next_multiple_of(DIRECT_ALIGNMENT)
+            .unwrap_or(chunk_size)
+    } else {
+        chunk_size
+    };
+    #[cfg(target_os = "linux")]
+    {
+        if options.nocache {
+            // This is purely advisory, so there's no harm in ignoring any errors.
+            let _ = fcntl::posix_fadvise(
+                source_file.as_raw_fd(),
+                0,
+                0,
+                PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+            );
+        }
+        // Pre-allocating the destination both avoids fragmentation and ensures we fail fast if
+        // there is insufficient space, rather than after having copied a large portion of the
+        // file.
+        match fcntl::fallocate(
+            dest_file.as_raw_fd(),
+            FallocateFlags::empty(),
+            0,
+            len as libc::off_t,
+        ) {
+            // Allocating an empty range is an error, but there's nothing to allocate anyways.
+            Err(Errno::EINVAL) if len == 0 => {}
+            Ok(()) | Err(Errno::EOPNOTSUPP) => {}
+            Err(err) => return Err(dest_error_message(err.into())),
+        }
+    }
+    (0..len.div_ceil(chunk_size))
+        .into_par_iter()
+        .map(|chunk| {
+            let offset = chunk * chunk_size;
+            let _permits = limits::acquire_devices(&source_file, &dest_file);
+            copy_range(
+                &source_file,
+                &dest_file,
+                offset,
+                cmp::min(chunk_size, len - offset),
+                strategy,
+            )
+            .map_err(error_message)
+        })
+        .collect::<Result<()>>()?;
+    if direct {
+        // Direct I/O requires writing whole blocks, so the final block may have been padded.
+        dest_file.set_len(len).map_err(dest_error_message)?;
+    }
+    set_permissions(&dest_file, mode).map_err(dest_error_message)
+}
+
+/// Open `source` for reading and create `dest` for writing, with `O_DIRECT` if requested and
+/// supported by both files. The returned boolean indicates whether `O_DIRECT` is in use.
+fn open(
+    source: Location,
+    dest: Location,
+    mode: u32,
+    options: &Options,
+) -> Result<(OpenFile, OpenFile, bool)> {
+    #[cfg(target_os = "linux")]
+    if options.direct {
+        let flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_DIRECT;
+        let source_file = match source.open_with(flags, 0) {
+            Ok(file) => file,
+            // The filesystem doesn't support `O_DIRECT` (e.g. tmpfs).
+            Err(Errno::EINVAL) => return Ok((source.open()?, dest.create(mode)?, false)),
+            Err(err) => return Err(source.error(io::Error::from(err))),
+        };
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_DIRECT;
+        return match dest.open_with(flags, mode) {
+            Ok(dest_file) => Ok((source_file, dest_file, true)),
+            Err(Errno::EINVAL) => {
+                disable_direct(&source_file).map_err(|err| source.error(err))?;
+                Ok((source_file, dest.create(mode)?, false))
+            }
+            Err(err) => Err(dest.error(io::Error::from(err))),
+        };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = options;
+    Ok((source.open()?, dest.create(mode)?, false))
+}
+
+#[cfg(target_os = "linux")]
+fn disable_direct(file: &File) -> io::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl::fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl::fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(flags - OFlag::O_DIRECT))?;
+    Ok(())
+}
+
+/// Evict the given range of both files from the page cache, first waiting for the range of `dest`
+/// to be written out, as dirty pages cannot be evicted.
+fn drop_cache(source: &File, dest: &File, offset: u64, len: u64) {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: `sync_file_range` only operates on the given file descriptor, which is valid
+        // for as long as `dest` is. Errors are ignored as this is purely advisory.
+        unsafe {
+            libc::sync_file_range(
+                dest.as_raw_fd(),
+                offset as libc::off64_t,
+                len as libc::off64_t,
+                libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                    | libc::SYNC_FILE_RANGE_WRITE
+                    | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+            );
+        }
+        for file in [source, dest] {
+            let _ = fcntl::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            );
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (source, dest, offset, len);
+}
+
+/// Copy `len` bytes starting at `offset` from `source` into the same position in `dest`.
+#[cfg(target_os = "linux")]
+fn copy_range(
+    source: &File,
+    dest: &File,
+    offset: u64,
+    len: u64,
+    strategy: Strategy,
+) -> io::Result<()> {
+    if strategy.direct {
+        return copy_range_direct(source, dest, offset, len, strategy);
+    }
+    let (mut source_offset, mut dest_offset) = (offset as i64, offset as i64);
+    let mut remaining = len;
+    while remaining > 0 {
+        let to_copy = cmp::min(remaining, strategy.block_size);
+        strategy.block(to_copy);
+        let block_offset = source_offset as u64;
+        match fcntl::copy_file_range(
+            source.as_raw_fd(),
+            Some(&mut source_offset),
+            dest.as_raw_fd(),
+            Some(&mut dest_offset),
+            to_copy as usize,
+        ) {
+            // The source file was truncated while we were copying it.
+            Ok(0) => return Ok(()),
+            Ok(copied) => {
+                remaining -= copied as u64;
+                if strategy.nocache {
+                    drop_cache(source, dest, block_offset, copied as u64);
+                }
+            }
+            Err(Errno::EINTR) => {}
+            // Older kernels don't support `copy_file_range` across filesystems, and some
+            // filesystems don't support it at all, so we fall back to a plain read/write loop.
+            Err(Errno::EXDEV | Errno::ENOSYS | Errno::EOPNOTSUPP | Errno::EINVAL) => {
+                return copy_range_buffered(
+                    source,
+                    dest,
+                    source_offset as u64,
+                    remaining,
+                    strategy,
+                );
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_range(
+    source: &File,
+    dest: &File,
+    offset: u64,
+    len: u64,
+    strategy: Strategy,
+) -> io::Result<()> {
+    copy_range_buffered(source, dest, offset, len, strategy)
+}
+
+/// Copy a range using direct I/O, which bypasses the page cache entirely. `offset` must be a
+/// multiple of `DIRECT_ALIGNMENT`. Should the filesystem turn out to reject direct I/O for the
+/// files in question, we fall back to copying the range normally.
+#[cfg(target_os = "linux")]
+fn copy_range_direct(
+    source: &File,
+    dest: &File,
+    mut offset: u64,
+    len: u64,
+    strategy: Strategy,
+) -> io::Result<()> {
+    let end = offset + len;
+    // As elsewhere, no more than a block is copied between checks of the bandwidth limit.
+    let buffer_size = cmp::min(DIRECT_BUFFER_SIZE, strategy.block_size)
+        .next_multiple_of(DIRECT_ALIGNMENT) as usize;
+    let mut storage = vec![0; buffer_size + DIRECT_ALIGNMENT as usize];
+    let start = storage.as_ptr().align_offset(DIRECT_ALIGNMENT as usize);
+    let buffer = &mut storage[start..start + buffer_size];
+    while offset < end {
+        let to_copy = cmp::min(buffer.len() as u64, end - offset);
+        strategy.block(to_copy);
+        let to_read = to_copy.next_multiple_of(DIRECT_ALIGNMENT) as usize;
+        let result = source
+            .read_at(&mut buffer[..to_read], offset)
+            .and_then(|read| {
+                // A short read only occurs at the end of the file, in which case we pad the write
+                // out to a whole block, with the excess being truncated once all ranges have been
+                // copied.
+                let to_write = (read as u64).next_multiple_of(DIRECT_ALIGNMENT) as usize;
+                buffer[read..to_write].fill(0);
+                dest.write_all_at(&buffer[..to_write], offset)?;
+                Ok(read)
+            });
+        match result {
+            Ok(0) => return Ok(()),
+            Ok(read) => offset += read as u64,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                disable_direct(source)?;
+                disable_direct(dest)?;
+                let strategy = Strategy {
+                    direct: false,
+                    ..strategy
+                };
+                return copy_range(source, dest, offset, end - offset, strategy);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn copy_range_buffered(
+    source: &File,
+    dest: &File,
+    mut offset: u64,
+    len: u64,
+    strategy: Strategy,
+) -> io::Result<()> {
+    let end = offset + len;
+    let mut buffer = vec![0; cmp::min(len, throttle::BLOCK_SIZE) as usize];
+    while offset < end {
+        let to_read = cmp::min(buffer.len() as u64, end - offset) as usize;
+        let read = match source.read_at(&mut buffer[..to_read], offset) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        strategy.block(read as u64);
+        dest.write_all_at(&buffer[..read], offset)?;
+        if strategy.nocache {
+            drop_cache(source, dest, offset, read as u64);
+        }
+        offset += read as u64;
+    }
+    Ok(())
+}
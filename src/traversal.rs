@@ -0,0 +1,486 @@
+//! Traversal of the directory trees being copied. Rather than recursing into each subdirectory
+//! (which for deeply nested trees risks overflowing the stacks of rayon's worker threads), copying
+//! a tree is split up into units of work which are spawned as rayon tasks or, once too many are
+//! in flight, pushed onto a stack belonging to the current task. Directories are read a batch of
+//! entries at a time, so neither the memory used nor the number of tasks depends on the number
+//! of entries in a directory. The exception is a case-insensitive destination, where each
+//! directory is read in full so that the names of its entries can be resolved up front.
+
+use crate::error::Result;
+use crate::filesystem::{self as fs, Dir, Entries, Entry, FileType};
+use crate::limits::{self, Semaphore};
+use crate::options::Options;
+use crate::sanitize::Claimed;
+use crate::{copy_file, report};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rayon::{Scope, Yield};
+use std::cmp;
+use std::collections::{BinaryHeap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::ops::BitOr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+/// The maximum number of entries read from a directory at a time.
+const BATCH_SIZE: usize = 128;
+
+/// The number of units of work which may be in flight at once for each thread in the pool.
+const IN_FLIGHT_PER_THREAD: usize = 4;
+
+/// The number of file descriptors set aside for each unit of work in flight, which holds open the
+/// directories it copies between along with the files being copied. When descriptors are scarce
+/// less work is put in flight, so that they aren't all tied up in open directories.
+const DESCRIPTORS_PER_WORK: usize = 8;
+
+/// An entry read from a directory, along with the name it's to be copied to should that differ
+/// from its own (as resolved by `Traversal::resolve_names`).
+type Named = (Entry, Option<OsString>);
+
+enum Work {
+    /// A directory named `name` in `source` to be copied into `dest` (as `dest_name`, should that
+    /// differ), which may already have been created there.
+    Directory {
+        source: Arc<Dir>,
+        dest: Arc<Dir>,
+        name: OsString,
+        dest_name: Option<OsString>,
+        created: bool,
+    },
+    /// A directory whose entries are in the process of being read.
+    Read {
+        source: Arc<Dir>,
+        dest: Arc<Dir>,
+        entries: Entries,
+    },
+    /// Entries read from `source` which are to be copied into `dest`.
+    Batch {
+        source: Arc<Dir>,
+        dest: Arc<Dir>,
+        entries: Vec<Named>,
+    },
+}
+
+/// A file queued to be copied from a HDD, as with `Options::hdd`.
+struct Queued {
+    inode: u64,
+    source: Arc<Dir>,
+    dest: Arc<Dir>,
+    name: OsString,
+    dest_name: OsString,
+    file_type: Result<FileType>,
+}
+
+// Queued files are ordered by inode number, lowest first (as `BinaryHeap` is a max-heap).
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other.inode.cmp(&self.inode)
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.inode == other.inode
+    }
+}
+
+impl Eq for Queued {}
+
+/// The files queued to be copied from a single source device.
+#[derive(Default)]
+struct DeviceQueue {
+    files: BinaryHeap<Queued>,
+    /// Whether a task is copying the queued files, in which case others only add to the queue.
+    draining: bool,
+}
+
+struct Traversal<'a> {
+    options: &'a Options,
+    in_flight: Semaphore,
+    has_err: AtomicBool,
+    /// The files waiting to be copied from each source device when `Options::hdd` is set.
+    queues: Mutex<HashMap<u64, DeviceQueue>>,
+    /// Notified whenever a file is taken from a queue, making room for those waiting to add more.
+    queue_space: Condvar,
+    /// The number of files which may be queued for a device before the tasks adding to its queue
+    /// wait for it to drain. Each task adds a batch of files from a single directory, so this also
+    /// limits the number of directories held open by queued files, just as for work in flight.
+    queue_limit: usize,
+}
+
+/// Copy the contents of the directory `source` into the directory `dest`.
+pub fn copy_contents(source: Dir, dest: Dir, options: &Options) -> Result<bool> {
+    let entries = source.read()?;
+    let in_flight = cmp::min(
+        IN_FLIGHT_PER_THREAD * rayon::current_num_threads(),
+        limits::descriptor_limit() / DESCRIPTORS_PER_WORK,
+    );
+    let traversal = Traversal {
+        options,
+        in_flight: Semaphore::new(in_flight),
+        has_err: AtomicBool::new(false),
+        queues: Mutex::new(HashMap::new()),
+        queue_space: Condvar::new(),
+        queue_limit: cmp::max(1, in_flight),
+    };
+    let (source, dest) = (Arc::new(source), Arc::new(dest));
+    rayon::scope(|scope| {
+        traversal.run(
+            scope,
+            Work::Read {
+                source,
+                dest,
+                entries,
+            },
+        )
+    });
+    Ok(traversal.has_err.into_inner())
+}
+
+impl<'a> Traversal<'a> {
+    fn report(&self, result: Result<bool>) {
+        if report(result) {
+            self.has_err.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Perform `work`, along with any further work it gives rise to which isn't spawned as a
+    /// separate task. The stack is processed depth-first, so it never holds more than a few
+    /// batches' worth of work for each level of the tree.
+    fn run<'s>(&'s self, scope: &Scope<'s>, work: Work) {
+        let mut stack = vec![work];
+        while let Some(work) = stack.pop() {
+            match work {
+                Work::Directory {
+                    source,
+                    dest,
+                    name,
+                    dest_name,
+                    created,
+                } => {
+                    let dest_name = dest_name.as_deref().unwrap_or(&name);
+                    match open_directory(&source, &dest, &name, dest_name, created) {
+                        Ok((source, dest, entries)) => stack.push(Work::Read {
+                            source: Arc::new(source),
+                            dest: Arc::new(dest),
+                            entries,
+                        }),
+                        Err(err) => self.report(Err(err)),
+                    }
+                }
+                Work::Read {
+                    source,
+                    dest,
+                    entries,
+                } if self.options.case_insensitive => {
+                    let mut named = self.resolve_names(&source, entries).into_iter();
+                    loop {
+                        let batch: Vec<_> = named.by_ref().take(BATCH_SIZE).collect();
+                        if batch.is_empty() {
+                            break;
+                        }
+                        let work = Work::Batch {
+                            source: source.clone(),
+                            dest: dest.clone(),
+                            entries: batch,
+                        };
+                        self.submit(scope, &mut stack, work);
+                    }
+                }
+                Work::Read {
+                    source,
+                    dest,
+                    mut entries,
+                } => {
+                    let (mut batch, mut read) = (Vec::new(), 0);
+                    for entry in entries.by_ref().take(BATCH_SIZE) {
+                        read += 1;
+                        match entry {
+                            Ok(entry) => batch.push((entry, None)),
+                            Err(err) => self.report(Err(err)),
+                        }
+                    }
+                    // A short batch means we've reached the end of the directory. Otherwise the
+                    // batch ends up on top of the stack (if not spawned), so that it's copied
+                    // before any more entries are read.
+                    if read == BATCH_SIZE {
+                        stack.push(Work::Read {
+                            source: source.clone(),
+                            dest: dest.clone(),
+                            entries,
+                        });
+                    }
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    let work = Work::Batch {
+                        source,
+                        dest,
+                        entries: batch,
+                    };
+                    self.submit(scope, &mut stack, work);
+                }
+                Work::Batch {
+                    source,
+                    dest,
+                    entries,
+                } => self.copy_batch(scope, &mut stack, source, dest, entries),
+            }
+        }
+    }
+
+    /// Spawn `work` as a separate task if the limit on work in flight allows it, and otherwise
+    /// leave it for the current task to do.
+    fn submit<'s>(&'s self, scope: &Scope<'s>, stack: &mut Vec<Work>, work: Work) {
+        match self.in_flight.try_acquire() {
+            Some(permit) => scope.spawn(move |scope| {
+                let _permit = permit;
+                self.run(scope, work);
+            }),
+            None => stack.push(work),
+        }
+    }
+
+    /// Read the remaining `entries` of `source` in full, and resolve the names they're to be
+    /// copied to in a case-insensitive destination. This is done in order of their names before
+    /// any of them are copied, so that which of the entries whose names collide is renamed (or
+    /// reported) is the same from one copy to the next, rather than depending on which happens
+    /// to be copied first.
+    fn resolve_names(&self, source: &Dir, entries: Entries) -> Vec<Named> {
+        let mut read = Vec::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => read.push(entry),
+                Err(err) => self.report(Err(err)),
+            }
+        }
+        read.sort_unstable_by(|(a, ..), (b, ..)| a.cmp(b));
+        let mut claimed = Claimed::default();
+        let mut named = Vec::with_capacity(read.len());
+        for entry in read {
+            let dest_name = match claimed.claim(source, &entry.0, self.options) {
+                Ok(dest_name) if dest_name == entry.0 => None,
+                Ok(dest_name) => Some(dest_name.into_owned()),
+                Err(err) => {
+                    self.report(Err(err));
+                    continue;
+                }
+            };
+            named.push((entry, dest_name));
+        }
+        named
+    }
+
+    fn copy_batch<'s>(
+        &'s self,
+        scope: &Scope<'s>,
+        stack: &mut Vec<Work>,
+        source: Arc<Dir>,
+        dest: Arc<Dir>,
+        entries: Vec<Named>,
+    ) {
+        let options = self.options;
+        let (mut directories, mut files) = (Vec::new(), Vec::new());
+        for (entry, dest_name) in entries {
+            match entry {
+                (name, _, Ok(FileType::Directory)) => directories.push((name, dest_name, false)),
+                entry => files.push((entry, dest_name)),
+            }
+        }
+        // Names are never resolved to others when using io_uring, which isn't used for
+        // case-insensitive destinations.
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if options.io_uring {
+            let names = directories.into_iter().map(|(name, ..)| name).collect();
+            let (created, not_created) = create_directories(&source, &dest, names, self);
+            directories = created.into_iter().map(|name| (name, None, true)).collect();
+            directories.extend(not_created.into_iter().map(|name| (name, None, false)));
+        }
+        // The directories are submitted first so that other threads can start on them while this
+        // one copies the files.
+        for (name, dest_name, created) in directories {
+            let work = Work::Directory {
+                source: source.clone(),
+                dest: dest.clone(),
+                name,
+                dest_name,
+                created,
+            };
+            self.submit(scope, stack, work);
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if options.io_uring {
+            files = copy_regular_files(&source, &dest, files, self);
+        }
+        let copy_entry = |((name, _, file_type), dest_name): Named| {
+            let dest_name = dest_name.as_deref().unwrap_or(&name);
+            copy_file(source.join(&name), file_type, dest.join(dest_name), options)
+        };
+        if options.hdd {
+            let device = match source.device() {
+                Ok(device) => device,
+                Err(err) => return self.report(Err(err)),
+            };
+            let queued = files
+                .into_iter()
+                .map(|((name, inode, file_type), dest_name)| Queued {
+                    inode,
+                    source: source.clone(),
+                    dest: dest.clone(),
+                    dest_name: dest_name.unwrap_or_else(|| name.clone()),
+                    name,
+                    file_type,
+                })
+                .collect();
+            return self.copy_sequentially(device, queued);
+        }
+        let has_err = files
+            .into_par_iter()
+            .map(copy_entry)
+            .reduce(|| false, BitOr::bitor);
+        self.report(Ok(has_err));
+    }
+
+    /// Copy `files` from the source device `device` one at a time, in order of inode number. On a
+    /// HDD, reading many files at once results in the disk head constantly seeking back and forth
+    /// between them, whereas the order of inode numbers for most filesystems roughly corresponds
+    /// to the files' physical location on disk. Directories are still traversed in parallel, as
+    /// walking them is comparatively cheap and keeps the queue of files to copy full.
+    ///
+    /// The files are added to the device's queue, which is drained by whichever task finds it
+    /// idle, so other tasks carry on with their work rather than waiting for their turn. Each
+    /// device has its own queue, so copying from one doesn't hold up copying from another.
+    fn copy_sequentially(&self, device: u64, files: Vec<Queued>) {
+        let lock = || self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+        let full = |queues: &mut HashMap<u64, DeviceQueue>| {
+            queues
+                .get(&device)
+                .is_some_and(|queue| queue.draining && queue.files.len() >= self.queue_limit)
+        };
+        let mut queues = lock();
+        while full(&mut queues) {
+            drop(queues);
+            // Rather than blocking while the queue drains, this thread gets on with other work,
+            // only waiting for room in the queue once there is none.
+            queues = match rayon::yield_now() {
+                Some(Yield::Executed) => lock(),
+                _ => self
+                    .queue_space
+                    .wait_while(lock(), full)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        let queue = queues.entry(device).or_default();
+        queue.files.extend(files);
+        if queue.draining {
+            return;
+        }
+        queue.draining = true;
+        drop(queues);
+        let next = || {
+            let mut queues = lock();
+            let queue = queues.get_mut(&device)?;
+            let file = queue.files.pop();
+            queue.draining = file.is_some();
+            self.queue_space.notify_all();
+            file
+        };
+        while let Some(file) = next() {
+            let has_err = copy_file(
+                file.source.join(&file.name),
+                file.file_type,
+                file.dest.join(&file.dest_name),
+                self.options,
+            );
+            self.report(Ok(has_err));
+        }
+    }
+}
+
+/// Create the directory `name` of `source` in `dest` as `dest_name` (unless already `created`)
+/// and open the two, ready for reading the former's entries.
+fn open_directory(
+    source: &Dir,
+    dest: &Dir,
+    name: &OsStr,
+    dest_name: &OsStr,
+    created: bool,
+) -> Result<(Dir, Dir, Entries)> {
+    let (source, dest) = (source.join(name), dest.join(dest_name));
+    if !created {
+        dest.create_dir(fs::mode(&source.symlink_metadata()?))?;
+    }
+    let (source, dest) = (source.open_dir()?, dest.open_dir()?);
+    let entries = source.read()?;
+    Ok((source, dest, entries))
+}
+
+/// Create the directories `names` of `source` in `dest` using io_uring, returning the names of
+/// those created and those which need to be created by other means.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn create_directories(
+    source: &Dir,
+    dest: &Dir,
+    directories: Vec<OsString>,
+    traversal: &Traversal,
+) -> (Vec<OsString>, Vec<OsString>) {
+    use crate::uring;
+
+    let (mut created, mut not_created) = (Vec::new(), Vec::new());
+    for batch in directories.chunks(uring::BATCH_SIZE) {
+        let names = batch.iter().map(OsString::as_os_str).collect::<Vec<_>>();
+        match uring::create_dirs(source, dest, &names) {
+            None => not_created.extend(batch.iter().cloned()),
+            Some(results) => {
+                for (name, result) in batch.iter().zip(results) {
+                    match result {
+                        Ok(()) => created.push(name.clone()),
+                        Err(err) => traversal.report(Err(err)),
+                    }
+                }
+            }
+        }
+    }
+    (created, not_created)
+}
+
+/// Copy the regular files among `entries` from `source` into `dest` using io_uring, which batches
+/// together the system calls needed to do so. Returns the entries remaining to be copied by other
+/// means.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn copy_regular_files(
+    source: &Dir,
+    dest: &Dir,
+    entries: Vec<Named>,
+    traversal: &Traversal,
+) -> Vec<Named> {
+    use crate::uring;
+
+    let (files, mut remaining): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|((_, _, file_type), _)| matches!(file_type, Ok(FileType::Regular)));
+    for batch in files.chunks(uring::BATCH_SIZE) {
+        let names = batch
+            .iter()
+            .map(|((name, _, _), _)| name.as_os_str())
+            .collect::<Vec<_>>();
+        let results = match uring::copy_files(source, dest, &names) {
+            Some(results) => results,
+            None => batch.iter().map(|_| Ok(false)).collect(),
+        };
+        for (((name, inode, _), _), result) in batch.iter().zip(results) {
+            match result {
+                Ok(true) => {}
+                // The file couldn't be copied via io_uring, e.g. because it is too large.
+                Ok(false) => remaining.push(((name.clone(), *inode, Ok(FileType::Regular)), None)),
+                Err(err) => traversal.report(Err(err)),
+            }
+        }
+    }
+    remaining
+}
@@ -0,0 +1,724 @@
+//! An [io_uring](https://kernel.dk/io_uring.pdf) based backend for the operations which dominate
+//! the time spent copying trees of many small files. Rather than each file costing half a dozen
+//! blocking system calls (`open`, `fstat`, `open`, `copy_file_range`, `close`, `close`), the files
+//! of a directory are processed in batches, with each step for the whole batch being submitted to
+//! the kernel at once. Each rayon worker thread has its own ring.
+//!
+//! Only the subset of io_uring needed by `fcp` is implemented here, directly on top of the raw
+//! system calls. Should the kernel not support io_uring (or any of the operations we need), then
+//! the functions in this module return `None` and the caller falls back to the `filesystem`
+//! module.
+
+use crate::error::{Error, Result};
+use crate::filesystem::{self as fs, Dir, Location};
+use crate::limits;
+use nix::sys::stat::{self, Mode};
+use std::cell::RefCell;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::result;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+/// The maximum number of files processed per batch.
+pub const BATCH_SIZE: usize = 64;
+
+/// Regular files up to this size are copied entirely through the ring. The contents of larger
+/// files are copied with `copy_file_range` instead, for which the cost of opening the file is
+/// negligible in comparison anyways.
+const INLINE_COPY_LIMIT: u64 = 128 << 10;
+
+/// The number of submission queue entries in each ring, which must accommodate the two entries
+/// per file needed to copy a full batch.
+const RING_ENTRIES: u32 = 2 * BATCH_SIZE as u32;
+
+/// How often to check for the completion of operations in flight once waiting on the ring fails.
+const FAILED_WAIT_INTERVAL: Duration = Duration::from_millis(1);
+
+const IORING_OP_OPENAT: u8 = 18;
+const IORING_OP_CLOSE: u8 = 19;
+const IORING_OP_STATX: u8 = 21;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+const IORING_OP_MKDIRAT: u8 = 37;
+const REQUIRED_OPS: [u8; 6] = [
+    IORING_OP_OPENAT,
+    IORING_OP_CLOSE,
+    IORING_OP_STATX,
+    IORING_OP_READ,
+    IORING_OP_WRITE,
+    IORING_OP_MKDIRAT,
+];
+
+const IOSQE_IO_LINK: u8 = 1 << 2;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+const IORING_REGISTER_PROBE: libc::c_uint = 8;
+const IO_URING_OP_SUPPORTED: u16 = 1;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct SubmissionQueueOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct CompletionQueueOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct Parameters {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SubmissionQueueOffsets,
+    cq_off: CompletionQueueOffsets,
+}
+
+/// A submission queue entry, i.e. a single operation for the kernel to perform.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Submission {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry, i.e. the result of an operation.
+#[allow(dead_code)]
+#[repr(C)]
+struct Completion {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ProbeOp {
+    op: u8,
+    resv: u8,
+    flags: u16,
+    resv2: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct Probe {
+    last_op: u8,
+    ops_len: u8,
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [ProbeOp; 256],
+}
+
+/// The subset of `struct statx` we make use of, padded out to its full size.
+#[allow(dead_code)]
+#[repr(C)]
+struct Statx {
+    mask: u32,
+    blksize: u32,
+    attributes: u64,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    mode: u16,
+    pad1: u16,
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    attributes_mask: u64,
+    timestamps: [u64; 8],
+    rdev_major: u32,
+    rdev_minor: u32,
+    dev_major: u32,
+    dev_minor: u32,
+    rest: [u64; 14],
+}
+
+const _: () = assert!(mem::size_of::<Submission>() == 64);
+const _: () = assert!(mem::size_of::<Statx>() == 256);
+
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, offset: libc::off_t, len: usize) -> io::Result<Self> {
+        // SAFETY: We request a fresh shared mapping of the ring, which does not alias any memory
+        // owned by Rust. The result is checked for failure before use.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    /// # Safety
+    /// `offset` must lie within the mapping and be suitably aligned for a `T`.
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.cast::<u8>().add(offset as usize).cast()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: The mapping is exclusively owned by `self`, which is no longer usable.
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+struct Ring {
+    // The mappings must be dropped before the file descriptor is closed.
+    submission_ring: Mapping,
+    completion_ring: Mapping,
+    submissions: Mapping,
+    fd: OwnedFd,
+    parameters: Parameters,
+}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut parameters = Parameters::default();
+        // SAFETY: `parameters` is a valid `struct io_uring_params` for the kernel to fill in.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut parameters as *mut Parameters,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `io_uring_setup` returned a new file descriptor which nothing else owns.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let (sq_off, cq_off) = (&parameters.sq_off, &parameters.cq_off);
+        let submission_ring = Mapping::new(
+            fd.as_raw_fd(),
+            IORING_OFF_SQ_RING,
+            sq_off.array as usize + parameters.sq_entries as usize * mem::size_of::<u32>(),
+        )?;
+        let completion_ring = Mapping::new(
+            fd.as_raw_fd(),
+            IORING_OFF_CQ_RING,
+            cq_off.cqes as usize + parameters.cq_entries as usize * mem::size_of::<Completion>(),
+        )?;
+        let submissions = Mapping::new(
+            fd.as_raw_fd(),
+            IORING_OFF_SQES,
+            parameters.sq_entries as usize * mem::size_of::<Submission>(),
+        )?;
+        Ok(Ring {
+            submission_ring,
+            completion_ring,
+            submissions,
+            fd,
+            parameters,
+        })
+    }
+
+    /// Whether the kernel supports all of the operations we need.
+    fn supports_required_ops(&self) -> bool {
+        let mut probe = Probe {
+            last_op: 0,
+            ops_len: 0,
+            resv: 0,
+            resv2: [0; 3],
+            ops: [ProbeOp::default(); 256],
+        };
+        // SAFETY: `probe` is a valid `struct io_uring_probe` with room for 256 operations.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_PROBE,
+                &mut probe as *mut Probe,
+                256,
+            )
+        };
+        result == 0
+            && REQUIRED_OPS.iter().all(|&op| {
+                op <= probe.last_op && probe.ops[op as usize].flags & IO_URING_OP_SUPPORTED != 0
+            })
+    }
+
+    /// Submit all of `operations` and wait for them to complete, returning the result of each.
+    /// Should submitting them fail, those which weren't submitted are withdrawn and the results of
+    /// those which were are returned as the error, once they've completed.
+    ///
+    /// # Safety
+    /// All pointers referenced by `operations` must remain valid until this function returns.
+    unsafe fn run(&mut self, operations: &[Submission]) -> result::Result<Vec<i32>, Partial> {
+        let (sq_off, cq_off) = (&self.parameters.sq_off, &self.parameters.cq_off);
+        debug_assert!(operations.len() <= self.parameters.sq_entries as usize);
+        let sq_head = &*self.submission_ring.at::<AtomicU32>(sq_off.head);
+        let sq_tail = &*self.submission_ring.at::<AtomicU32>(sq_off.tail);
+        let sq_mask = *self.submission_ring.at::<u32>(sq_off.ring_mask);
+        let sq_array = self.submission_ring.at::<u32>(sq_off.array);
+        let tail = sq_tail.load(Ordering::Relaxed);
+        for (i, operation) in operations.iter().enumerate() {
+            let index = tail.wrapping_add(i as u32) & sq_mask;
+            *self.submissions.at::<Submission>(0).add(index as usize) = Submission {
+                user_data: i as u64,
+                ..*operation
+            };
+            *sq_array.add(index as usize) = index;
+        }
+        sq_tail.store(
+            tail.wrapping_add(operations.len() as u32),
+            Ordering::Release,
+        );
+
+        let cq_head = &*self.completion_ring.at::<AtomicU32>(cq_off.head);
+        let cq_tail = &*self.completion_ring.at::<AtomicU32>(cq_off.tail);
+        let cq_mask = *self.completion_ring.at::<u32>(cq_off.ring_mask);
+        let completions = self.completion_ring.at::<Completion>(cq_off.cqes);
+        let mut results = vec![0; operations.len()];
+        let (mut submitted, mut completed, mut failed) = (0, 0, false);
+        // Once submitting has failed, we only wait for the operations already submitted, as they
+        // reference the caller's memory.
+        while completed < submitted || (!failed && completed < operations.len()) {
+            let (to_submit, to_complete) = match failed {
+                false => (operations.len() - submitted, operations.len() - completed),
+                true => (0, submitted - completed),
+            };
+            let result = libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd.as_raw_fd(),
+                to_submit as libc::c_uint,
+                to_complete as libc::c_uint,
+                IORING_ENTER_GETEVENTS,
+                ptr::null::<libc::sigset_t>(),
+                0,
+            );
+            match result {
+                result if result >= 0 => submitted += result as usize,
+                _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+                _ if !failed => {
+                    // The entries the kernel hasn't consumed are withdrawn, so that they can't be
+                    // submitted along with a later batch of operations.
+                    sq_tail.store(sq_head.load(Ordering::Acquire), Ordering::Release);
+                    failed = true;
+                }
+                // Even when we can't wait on the ring, the operations in flight still complete
+                // on their own, so we check on them periodically rather than spinning.
+                _ => thread::sleep(FAILED_WAIT_INTERVAL),
+            }
+            let (mut head, tail) = (
+                cq_head.load(Ordering::Relaxed),
+                cq_tail.load(Ordering::Acquire),
+            );
+            while head != tail {
+                let completion = &*completions.add((head & cq_mask) as usize);
+                results[completion.user_data as usize] = completion.res;
+                head = head.wrapping_add(1);
+                completed += 1;
+            }
+            cq_head.store(head, Ordering::Release);
+        }
+        if failed {
+            results.truncate(submitted);
+            return Err(Partial(results));
+        }
+        Ok(results)
+    }
+}
+
+/// The results of the operations which were submitted before `Ring::run` failed, which are always
+/// the first of those given to it.
+struct Partial(Vec<i32>);
+
+/// Close the file descriptors opened by those of `results` which are successful opens.
+fn close_opened<'a>(results: impl Iterator<Item = &'a i32>) {
+    for &fd in results.filter(|&&result| result >= 0) {
+        // SAFETY: The kernel returned a new file descriptor which nothing else owns.
+        drop(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+}
+
+thread_local! {
+    static RING: RefCell<Option<Ring>> = RefCell::new(Ring::new(RING_ENTRIES).ok());
+}
+
+static UMASK: OnceLock<Option<u32>> = OnceLock::new();
+
+/// Whether io_uring can be used on this system. The first call must occur before any other
+/// threads start creating files, as determining the process's umask briefly changes it.
+pub fn is_supported() -> bool {
+    UMASK
+        .get_or_init(|| {
+            let ring = Ring::new(RING_ENTRIES).ok()?;
+            ring.supports_required_ops().then(|| {
+                let umask = stat::umask(Mode::empty());
+                stat::umask(umask);
+                umask.bits() as u32
+            })
+        })
+        .is_some()
+}
+
+/// Convert the names of directory entries (which can never contain NUL bytes) to C strings.
+fn to_cstrings(names: &[&OsStr]) -> Option<Vec<CString>> {
+    names
+        .iter()
+        .map(|name| CString::new(name.as_bytes()).ok())
+        .collect()
+}
+
+fn os_error(location: Location, result: i32) -> Error {
+    location.error(io::Error::from_raw_os_error(-result))
+}
+
+/// Whether `result` is the error given when there are no file descriptors available.
+fn is_exhausted(result: i32) -> bool {
+    result == -libc::EMFILE || result == -libc::ENFILE
+}
+
+fn open(dir: &Dir, name: &CString, flags: libc::c_int, mode: u32) -> Submission {
+    Submission {
+        opcode: IORING_OP_OPENAT,
+        fd: dir.as_raw_fd(),
+        addr: name.as_ptr() as u64,
+        len: mode,
+        op_flags: (flags | libc::O_CLOEXEC) as u32,
+        ..Submission::default()
+    }
+}
+
+fn statx(dir: &Dir, name: &CString, buffer: &mut Statx) -> Submission {
+    Submission {
+        opcode: IORING_OP_STATX,
+        fd: dir.as_raw_fd(),
+        addr: name.as_ptr() as u64,
+        off: buffer as *mut Statx as u64,
+        len: libc::STATX_MODE | libc::STATX_SIZE,
+        op_flags: libc::AT_SYMLINK_NOFOLLOW as u32,
+        ..Submission::default()
+    }
+}
+
+fn read_write(opcode: u8, fd: RawFd, buffer: &[u8], flags: u8) -> Submission {
+    Submission {
+        opcode,
+        flags,
+        fd,
+        addr: buffer.as_ptr() as u64,
+        len: buffer.len() as u32,
+        ..Submission::default()
+    }
+}
+
+fn close(fd: RawFd) -> Submission {
+    Submission {
+        opcode: IORING_OP_CLOSE,
+        fd,
+        ..Submission::default()
+    }
+}
+
+fn empty_statx() -> Statx {
+    // SAFETY: `Statx` consists solely of integers, for which all-zeroes is a valid value.
+    unsafe { mem::zeroed() }
+}
+
+fn with_ring<T>(f: impl FnOnce(&mut Ring) -> T) -> Option<T> {
+    RING.with(|ring| ring.borrow_mut().as_mut().map(f))
+}
+
+/// Copy each of the regular files `names` within the directory `source` into the directory
+/// `dest`. For each file the result indicates whether it was copied, with files that were not
+/// (e.g. because they are too large) needing to be copied by some other means. Returns `None` if
+/// io_uring is unavailable.
+pub fn copy_files(source: &Dir, dest: &Dir, names: &[&OsStr]) -> Option<Vec<Result<bool>>> {
+    let umask = (*UMASK.get()?)?;
+    let c_names = to_cstrings(names)?;
+    with_ring(|ring| {
+        let mut results: Vec<Result<bool>> = names.iter().map(|_| Ok(false)).collect();
+        // Files too large to copy inline, and those we ran out of file descriptors for, are left
+        // to be copied by other means (which wait for descriptors to become available).
+        let mut deferred = vec![false; names.len()];
+        // The descriptors opened by the ring count towards the limit on those open at once, with
+        // each file that would exceed it also being deferred.
+        let mut permits = Vec::new();
+        let mut acquire_descriptors = |indices: Vec<usize>, deferred: &mut [bool]| {
+            indices
+                .into_iter()
+                .filter(|&i| match limits::try_acquire_descriptor() {
+                    Some(permit) => {
+                        permits.extend(permit);
+                        true
+                    }
+                    None => {
+                        deferred[i] = true;
+                        false
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let pending = |results: &[Result<bool>], deferred: &[bool]| {
+            (0..names.len())
+                .filter(|&i| matches!(results[i], Ok(false)) && !deferred[i])
+                .collect::<Vec<_>>()
+        };
+
+        // Step 1: open and stat each source file.
+        let mut metadata: Vec<Statx> = (0..names.len()).map(|_| empty_statx()).collect();
+        let mut source_fds: Vec<Option<OwnedFd>> = (0..names.len()).map(|_| None).collect();
+        let indices = acquire_descriptors(pending(&results, &deferred), &mut deferred);
+        let operations = indices
+            .iter()
+            .flat_map(|&i| {
+                [
+                    open(source, &c_names[i], libc::O_RDONLY | libc::O_NOFOLLOW, 0),
+                    statx(source, &c_names[i], &mut metadata[i]),
+                ]
+            })
+            .collect::<Vec<_>>();
+        // SAFETY: The names and `metadata` outlive the call.
+        let completed = match unsafe { ring.run(&operations) } {
+            Ok(completed) => completed,
+            Err(Partial(completed)) => {
+                close_opened(completed.iter().step_by(2));
+                return None;
+            }
+        };
+        for (&i, completed) in indices.iter().zip(completed.chunks(2)) {
+            if completed[0] >= 0 {
+                // SAFETY: The kernel returned a new file descriptor which nothing else owns.
+                source_fds[i] = Some(unsafe { OwnedFd::from_raw_fd(completed[0]) });
+            }
+            // The sizes of files on pseudo-filesystems can't be trusted, so they're left to be
+            // read until EOF by other means, which also handle those that can't be read at all.
+            let dev = stat::makedev(metadata[i].dev_major.into(), metadata[i].dev_minor.into());
+            let pseudo = completed[1] >= 0
+                && fs::is_pseudo_filesystem(dev, || source.join(names[i]).open_parent());
+            match completed.iter().find(|&&result| result < 0) {
+                _ if pseudo => deferred[i] = true,
+                Some(&result) if is_exhausted(result) => deferred[i] = true,
+                Some(&result) => results[i] = Err(os_error(source.join(names[i]), result)),
+                None if metadata[i].size > INLINE_COPY_LIMIT => deferred[i] = true,
+                None => {}
+            }
+        }
+
+        // Step 2: create each destination file. Only those copied inline are created here, as
+        // the others would have to be reopened, which the permissions they're created with may
+        // not allow.
+        let mut dest_fds: Vec<Option<OwnedFd>> = (0..names.len()).map(|_| None).collect();
+        let indices = acquire_descriptors(pending(&results, &deferred), &mut deferred);
+        let operations = indices
+            .iter()
+            .map(|&i| {
+                let mode = u32::from(metadata[i].mode) & 0o7777;
+                let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+                open(dest, &c_names[i], flags, mode)
+            })
+            .collect::<Vec<_>>();
+        // SAFETY: The names outlive the call.
+        let completed = match unsafe { ring.run(&operations) } {
+            Ok(completed) => completed,
+            Err(Partial(completed)) => {
+                close_opened(completed.iter());
+                return None;
+            }
+        };
+        for (&i, &result) in indices.iter().zip(&completed) {
+            if is_exhausted(result) {
+                deferred[i] = true;
+            } else if result < 0 {
+                results[i] = Err(os_error(dest.join(names[i]), result));
+            } else {
+                // SAFETY: The kernel returned a new file descriptor which nothing else owns.
+                dest_fds[i] = Some(unsafe { OwnedFd::from_raw_fd(result) });
+            }
+        }
+
+        // Step 3: copy the contents of each small file by reading it into a buffer and then
+        // writing it out, with the write linked to the read so it only happens if the read
+        // returns the entire file (it could have changed since we checked its size).
+        let indices = pending(&results, &deferred);
+        let mut buffers = indices
+            .iter()
+            .map(|&i| vec![0u8; metadata[i].size as usize])
+            .collect::<Vec<_>>();
+        let operations = indices
+            .iter()
+            .zip(&mut buffers)
+            .filter(|(_, buffer)| !buffer.is_empty())
+            .flat_map(|(&i, buffer)| {
+                let source_fd = source_fds[i].as_ref().unwrap().as_raw_fd();
+                let dest_fd = dest_fds[i].as_ref().unwrap().as_raw_fd();
+                [
+                    read_write(IORING_OP_READ, source_fd, buffer, IOSQE_IO_LINK),
+                    read_write(IORING_OP_WRITE, dest_fd, buffer, 0),
+                ]
+            })
+            .collect::<Vec<_>>();
+        // SAFETY: The buffers outlive the call, and the file descriptors remain open.
+        let completed = unsafe { ring.run(&operations) }.ok()?;
+        let mut completed = completed.chunks(2);
+        for (&i, buffer) in indices.iter().zip(&buffers) {
+            if buffer.is_empty() {
+                results[i] = Ok(true);
+                continue;
+            }
+            let completed = completed.next().unwrap();
+            // If the write was cancelled because of a short read (or was itself short) we remove
+            // the file so it can be created afresh by other means, but any other error is
+            // reported.
+            results[i] = match (completed[0], completed[1]) {
+                (read, _) if read < 0 => Err(os_error(source.join(names[i]), read)),
+                (_, written) if written < 0 && written != -libc::ECANCELED => {
+                    Err(os_error(dest.join(names[i]), written))
+                }
+                (_, written) if written as usize == buffer.len() => Ok(true),
+                _ => dest.join(names[i]).remove_file().map(|()| false),
+            };
+        }
+
+        // The mode given when creating a file is subject to the umask, so in the (unusual) case
+        // the umask would mask out bits of the source's mode we set the permissions explicitly,
+        // matching the behavior of `fs::copy`.
+        for i in 0..names.len() {
+            let mode = u32::from(metadata[i].mode) & 0o7777;
+            if let (Ok(true), Some(dest_fd)) = (&results[i], &dest_fds[i]) {
+                if mode & umask != 0 {
+                    let mode = Mode::from_bits_truncate(mode as libc::mode_t);
+                    if let Err(err) = stat::fchmod(dest_fd.as_raw_fd(), mode) {
+                        results[i] = Err(dest.join(names[i]).error(io::Error::from(err)));
+                    }
+                }
+            }
+        }
+
+        // Step 4: close all of the files.
+        let fds = source_fds
+            .into_iter()
+            .chain(dest_fds)
+            .flatten()
+            .collect::<Vec<_>>();
+        let operations = fds
+            .iter()
+            .map(|fd| close(fd.as_raw_fd()))
+            .collect::<Vec<_>>();
+        // SAFETY: Closing file descriptors references no memory.
+        let closed = match unsafe { ring.run(&operations) } {
+            Ok(_) => fds.len(),
+            Err(Partial(completed)) => completed.len(),
+        };
+        // Ownership of those submitted was transferred to the ring, which closed them, while the
+        // rest are closed as usual when dropped.
+        let mut fds = fds;
+        for fd in fds.drain(..closed) {
+            mem::forget(fd);
+        }
+        Some(results)
+    })
+    .flatten()
+}
+
+/// Create a directory in `dest` for each of the directories `names` within `source`, with the same
+/// permissions. Returns `None` if io_uring is unavailable.
+pub fn create_dirs(source: &Dir, dest: &Dir, names: &[&OsStr]) -> Option<Vec<Result<()>>> {
+    UMASK.get()?.as_ref()?;
+    let c_names = to_cstrings(names)?;
+    with_ring(|ring| {
+        let mut metadata: Vec<Statx> = (0..names.len()).map(|_| empty_statx()).collect();
+        let operations = c_names
+            .iter()
+            .zip(&mut metadata)
+            .map(|(name, metadata)| statx(source, name, metadata))
+            .collect::<Vec<_>>();
+        // SAFETY: The names and `metadata` outlive the call.
+        let mut results = unsafe { ring.run(&operations) }
+            .ok()?
+            .into_iter()
+            .enumerate()
+            .map(|(i, result)| match result {
+                result if result < 0 => Err(os_error(source.join(names[i]), result)),
+                _ => Ok(()),
+            })
+            .collect::<Vec<_>>();
+
+        let indices = (0..names.len())
+            .filter(|&i| results[i].is_ok())
+            .collect::<Vec<_>>();
+        let operations = indices
+            .iter()
+            .map(|&i| Submission {
+                opcode: IORING_OP_MKDIRAT,
+                fd: dest.as_raw_fd(),
+                addr: c_names[i].as_ptr() as u64,
+                len: u32::from(metadata[i].mode) & 0o7777,
+                ..Submission::default()
+            })
+            .collect::<Vec<_>>();
+        // SAFETY: The names outlive the call.
+        let completed = unsafe { ring.run(&operations) }.ok()?;
+        for (&i, &result) in indices.iter().zip(&completed) {
+            if result < 0 {
+                results[i] = Err(os_error(dest.join(names[i]), result));
+            }
+        }
+        Some(results)
+    })
+    .flatten()
+}
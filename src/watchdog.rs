@@ -0,0 +1,211 @@
+//! Detection of operations which take longer than `--op-timeout`, as happens when a network or
+//! FUSE filesystem stops responding. A hung system call can't be cancelled, so the best we can do
+//! is report it and carry on copying everything else without it. Once nothing but hung operations
+//! remain, the copy is abandoned rather than waiting on them forever.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// The longest interval between checks for operations which have timed out.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct InFlight {
+    description: String,
+    // The thread performing the operation.
+    thread: ThreadId,
+    last_progress: Instant,
+    timed_out: bool,
+}
+
+struct State {
+    next_id: u64,
+    in_flight: HashMap<u64, InFlight>,
+    // When an operation last started, progressed, or finished, or a sleep last ended.
+    last_activity: Instant,
+    // The threads deliberately sleeping, e.g. to stay within a rate limit or before retrying an
+    // operation, which are bound to make progress once they wake up.
+    sleeping: Vec<ThreadId>,
+    // Told that the copy has stalled, so that it can be abandoned.
+    stalled: Option<Sender<bool>>,
+}
+
+struct Watchdog {
+    timeout: Duration,
+    state: Mutex<State>,
+}
+
+impl Watchdog {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn run(&self) {
+        let interval = cmp::min(self.timeout / 4, MAX_CHECK_INTERVAL);
+        loop {
+            thread::sleep(interval);
+            let mut state = self.lock();
+            let now = Instant::now();
+            let State {
+                in_flight,
+                sleeping,
+                ..
+            } = &mut *state;
+            for operation in in_flight.values_mut() {
+                if !operation.timed_out
+                    && !sleeping.contains(&operation.thread)
+                    && now - operation.last_progress >= self.timeout
+                {
+                    operation.timed_out = true;
+                    TIMED_OUT.store(true, Ordering::Relaxed);
+                    eprintln!(
+                        "{}: operation timed out after {:?}",
+                        operation.description, self.timeout
+                    );
+                }
+            }
+            // If every operation still in flight has timed out and there has been no other
+            // activity in the meantime, then the rest of the copy is done (or is waiting on the
+            // hung operations), so there's nothing left to wait for.
+            let stalled = !state.in_flight.is_empty()
+                && state
+                    .in_flight
+                    .values()
+                    .all(|operation| operation.timed_out)
+                && state.sleeping.is_empty()
+                && now - state.last_activity >= self.timeout;
+            if stalled {
+                if let Some(stalled) = state.stalled.take() {
+                    let _ = stalled.send(true);
+                }
+            }
+        }
+    }
+}
+
+static WATCHDOG: OnceLock<Watchdog> = OnceLock::new();
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// Report operations taking longer than `timeout`, with a `timeout` of zero meaning no limit. Only
+/// the first call has any effect.
+pub fn configure(timeout: Duration) {
+    if timeout.is_zero() || WATCHDOG.get().is_some() {
+        return;
+    }
+    let watchdog = WATCHDOG.get_or_init(|| Watchdog {
+        timeout,
+        state: Mutex::new(State {
+            next_id: 0,
+            in_flight: HashMap::new(),
+            last_activity: Instant::now(),
+            sleeping: Vec::new(),
+            stalled: None,
+        }),
+    });
+    thread::spawn(|| watchdog.run());
+}
+
+/// Whether operations are being watched at all.
+pub fn is_enabled() -> bool {
+    WATCHDOG.get().is_some()
+}
+
+/// Whether any operation has timed out.
+pub fn timed_out() -> bool {
+    TIMED_OUT.load(Ordering::Relaxed)
+}
+
+/// Perform `copy`, which returns whether an error occurred. Should it stall with nothing but
+/// operations which have timed out remaining, it's abandoned (as those operations can't be
+/// cancelled) and treated as having failed, which they were already reported as.
+pub fn run(copy: impl FnOnce() -> bool + Send + 'static) -> bool {
+    let watchdog = match WATCHDOG.get() {
+        Some(watchdog) => watchdog,
+        None => return copy(),
+    };
+    let (sender, receiver) = mpsc::channel();
+    watchdog.lock().stalled = Some(sender.clone());
+    thread::spawn(move || {
+        let _ = sender.send(copy());
+    });
+    receiver.recv().unwrap_or(true)
+}
+
+/// Sleep for `duration`, which doesn't count against the timeout of any operation this thread is
+/// performing, nor as the copy having stalled.
+pub fn sleep(duration: Duration) {
+    let watchdog = match WATCHDOG.get() {
+        Some(watchdog) => watchdog,
+        None => return thread::sleep(duration),
+    };
+    let current = thread::current().id();
+    watchdog.lock().sleeping.push(current);
+    thread::sleep(duration);
+    let mut state = watchdog.lock();
+    let now = Instant::now();
+    state.sleeping.retain(|&thread| thread != current);
+    state.last_activity = now;
+    for operation in state.in_flight.values_mut() {
+        if operation.thread == current {
+            operation.last_progress = now;
+        }
+    }
+}
+
+/// An operation being watched, which stops being watched once dropped.
+#[must_use]
+pub struct Operation {
+    watchdog: &'static Watchdog,
+    id: u64,
+}
+
+impl Operation {
+    /// Restart the timeout, for long-running operations which are nonetheless making progress.
+    pub fn progress(&self) {
+        let mut state = self.watchdog.lock();
+        let now = Instant::now();
+        state.last_activity = now;
+        if let Some(operation) = state.in_flight.get_mut(&self.id) {
+            operation.last_progress = now;
+        }
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let mut state = self.watchdog.lock();
+        state.in_flight.remove(&self.id);
+        state.last_activity = Instant::now();
+    }
+}
+
+/// Start watching an operation, with `describe` giving the file(s) it operates on for use in
+/// reporting it should it time out. Returns `None` if operations aren't being watched.
+pub fn start(describe: impl FnOnce() -> String) -> Option<Operation> {
+    let watchdog = WATCHDOG.get()?;
+    let description = describe();
+    let mut state = watchdog.lock();
+    let (id, now) = (state.next_id, Instant::now());
+    state.next_id += 1;
+    state.last_activity = now;
+    state.in_flight.insert(
+        id,
+        InFlight {
+            description,
+            thread: thread::current().id(),
+            last_progress: now,
+            timed_out: false,
+        },
+    );
+    Some(Operation { watchdog, id })
+}
+
+/// Perform `operation`, watching it for as long as it takes.
+pub fn watch<T>(describe: impl FnOnce() -> String, operation: impl FnOnce() -> T) -> T {
+    let _operation = start(describe);
+    operation()
+}
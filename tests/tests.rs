@@ -21,11 +21,17 @@
 
 use dev_utils::*;
 use fcp::{self, filesystem as fs};
+use std::cmp;
 use std::ffi::OsStr;
+use std::fs::Permissions;
 use std::io::prelude::*;
-use std::path::Path;
-use std::process::{Command, ExitStatus};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 use std::string::String;
+use std::thread;
+use std::time::{Duration, Instant};
 
 const FILE_MODE: u32 = 0o644;
 const DIR_MODE: u32 = 0o755;
@@ -59,11 +65,100 @@ fn fcp_run<T: AsRef<OsStr>>(args: &[T]) -> CommandResult {
     }
 }
 
+/// The absolute path of `path`, for tests which run `fcp` from within the source directory, where
+/// a relative path would no longer refer to the same file.
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap()
+}
+
 fn copy_fixture(filename: &str) -> CommandResult {
+    copy_fixture_with_options(filename, &[])
+}
+
+fn copy_fixture_with_options(filename: &str, options: &[&str]) -> CommandResult {
     let filename = filename.strip_suffix(".json").unwrap();
     let destination = COPIES_DIR.join(filename);
     remove(&destination);
-    fcp_run(&[HYDRATED_DIR.join(filename), destination])
+    let mut args = options.iter().map(OsStr::new).collect::<Vec<_>>();
+    let source = HYDRATED_DIR.join(filename);
+    args.extend([source.as_os_str(), destination.as_os_str()]);
+    fcp_run(&args)
+}
+
+/// Copy the fixture `filename` with `options`, asserting that the copy succeeds without error.
+fn assert_copy_succeeds(filename: &str, options: &[&str]) {
+    let result = copy_fixture_with_options(filename, options);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+}
+
+/// As with `assert_copy_succeeds`, also asserting that the copy is identical to the fixture.
+fn assert_copied(filename: &str, options: &[&str]) {
+    assert_copy_succeeds(filename, options);
+    assert!(diff(filename).success());
+}
+
+/// Copy the FIFOs `names`, created afresh in the directory `source`, into `destination` with
+/// `options`, asserting that only one is copied at a time. Returns the order they were copied in.
+/// Each FIFO is fed data up front, but only closed (letting its copy finish) once its copy is the
+/// only one to have copied that data.
+fn copy_fifos_one_at_a_time(
+    source: &Path,
+    destination: &Path,
+    names: &[&str],
+    options: &[&str],
+) -> Vec<String> {
+    remove(source);
+    remove(destination);
+    fs::create_dir(source, DIR_MODE).unwrap();
+    // Opening a FIFO for reading as well as writing doesn't wait for it to be opened for reading.
+    let mut writers = names
+        .iter()
+        .map(|&name| {
+            let path = source.join(name);
+            fs::mkfifo(&path, Permissions::from_mode(FILE_MODE)).unwrap();
+            let mut writer = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+            (name.to_owned(), writer)
+        })
+        .collect::<Vec<_>>();
+    let mut args = options.iter().map(OsStr::new).collect::<Vec<_>>();
+    args.extend([
+        OsStr::new("--copy-contents"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    let child = Command::new(fcp_executable_path())
+        .args(&args)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let copied = |name: &str| {
+        std::fs::read(destination.join(name)).is_ok_and(|contents| contents == name.as_bytes())
+    };
+    let mut order = Vec::new();
+    while !writers.is_empty() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let started = loop {
+            if let Some(index) = writers.iter().position(|(name, _)| copied(name)) {
+                break index;
+            }
+            assert!(Instant::now() < deadline, "no FIFO was copied");
+            thread::sleep(Duration::from_millis(10));
+        };
+        // Give the others time to be copied too, as they would be were they not held back.
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(writers.iter().filter(|(name, _)| copied(name)).count(), 1);
+        order.push(writers.remove(started).0);
+    }
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+    order
 }
 
 macro_rules! make_test {
@@ -97,6 +192,480 @@ make_test!(
     large_files
 );
 
+// The disk the fixtures reside on may be taken to be a HDD, for which files aren't split into
+// chunks, so `--no-hdd` is given wherever that matters.
+#[test]
+fn chunked_file() {
+    initialize();
+    let fixture_file = "chunked_file.json";
+    hydrate_fixture(fixture_file);
+    assert_copied(fixture_file, &["--no-hdd", "--chunk-size=1M"]);
+
+    // With the copy throttled, it takes several seconds to get through each chunk, so the start
+    // of every chunk being copied in much less time than that means they're copied in parallel.
+    let (source, destination) = (
+        HYDRATED_DIR.join("chunked_file"),
+        COPIES_DIR.join("chunked_file"),
+    );
+    remove(&destination);
+    let contents = std::fs::read(&source).unwrap();
+    let starts = (0..contents.len()).step_by(1 << 20).collect::<Vec<_>>();
+    assert_eq!(starts.len(), 6);
+    let mut child = Command::new(fcp_executable_path())
+        .args(["--no-hdd", "--chunk-size=1M", "--bwlimit=256K", "-j6"])
+        .args([&source, &destination])
+        .spawn()
+        .unwrap();
+    let started = |copy: &[u8]| {
+        starts.iter().all(|&start| {
+            let end = cmp::min(start + 16, contents.len());
+            copy.get(start..end) == Some(&contents[start..end])
+        })
+    };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !std::fs::read(&destination).is_ok_and(|copy| started(&copy)) {
+        assert!(
+            Instant::now() < deadline,
+            "chunks weren't copied in parallel"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn hdd() {
+    initialize();
+    let fixture_file = "hdd.json";
+    hydrate_fixture(fixture_file);
+    assert_copied(fixture_file, &["--hdd"]);
+
+    // Files are copied one at a time in order of inode number, despite the threads available.
+    let (source, destination) = (HYDRATED_DIR.join("hdd_fifos"), COPIES_DIR.join("hdd_fifos"));
+    let order = copy_fifos_one_at_a_time(
+        &source,
+        &destination,
+        &["a", "b", "c", "d"],
+        &["--hdd", "-j4"],
+    );
+    let inode = |name: &String| std::fs::symlink_metadata(source.join(name)).unwrap().ino();
+    assert!(order
+        .windows(2)
+        .all(|pair| inode(&pair[0]) < inode(&pair[1])));
+}
+
+#[test]
+fn device_limits() {
+    initialize();
+    let fixture_file = "device_limits.json";
+    hydrate_fixture(fixture_file);
+    assert_copied(
+        fixture_file,
+        &[
+            "-j",
+            "2",
+            "--no-hdd",
+            "--chunk-size=1M",
+            "--source-device-limit=1",
+            "--dest-device-limit=1",
+        ],
+    );
+
+    // The files reside on the same device, so only one is copied at a time despite the threads
+    // available.
+    let (source, destination) = (
+        HYDRATED_DIR.join("device_limits_fifos"),
+        COPIES_DIR.join("device_limits_fifos"),
+    );
+    for limit in ["--source-device-limit=1", "--dest-device-limit=1"] {
+        let options = ["--no-hdd", "-j4", limit];
+        copy_fifos_one_at_a_time(&source, &destination, &["a", "b", "c", "d"], &options);
+    }
+}
+
+#[test]
+fn invalid_jobs() {
+    initialize();
+    let result = fcp_run(&["--jobs=many", "source", "destination"]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("invalid number"));
+}
+
+#[test]
+fn bandwidth_limit() {
+    initialize();
+    let fixture_file = "bandwidth_limit.json";
+    hydrate_fixture(fixture_file);
+    let start = Instant::now();
+    // The first second's worth of data is copied immediately, so the remaining 512K should take
+    // approximately one second. Only a lower bound is checked, and a generous one at that, as a
+    // loaded machine can make the copy take arbitrarily long.
+    assert_copied(fixture_file, &["--bwlimit=512K"]);
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    let limits_file = COPIES_DIR.join("bandwidth_limit_limits");
+    fs::create(&limits_file, FILE_MODE)
+        .unwrap()
+        .write_all(b"# Unlimited\nbwlimit=0\nbogus=1\n")
+        .unwrap();
+    let result = copy_fixture_with_options(
+        fixture_file,
+        &[&format!("--limits-file={}", limits_file.display())],
+    );
+    assert!(!result.success);
+    assert!(result.stderr.contains("invalid line 'bogus=1'"));
+}
+
+#[test]
+fn io_uring() {
+    initialize();
+    let fixture_file = "io_uring.json";
+    hydrate_fixture(fixture_file);
+    for options in [&["--no-hdd"][..], &["--no-hdd", "--no-io-uring"]] {
+        assert_copied(fixture_file, options);
+    }
+
+    // io_uring does away with the write system calls (or `copy_file_range`) made for each file.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        let (source, destination) = (HYDRATED_DIR.join("io_uring"), COPIES_DIR.join("io_uring"));
+        let write_syscalls = |options: &[&str]| {
+            remove(&destination);
+            let mut child = Command::new(fcp_executable_path())
+                .args(options)
+                .args([&source, &destination])
+                .spawn()
+                .unwrap();
+            // The process's I/O counters can still be read once it has exited, until it's reaped.
+            let stat = format!("/proc/{}/stat", child.id());
+            let exited = || std::fs::read_to_string(&stat).unwrap().contains(") Z ");
+            while !exited() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let io = std::fs::read_to_string(format!("/proc/{}/io", child.id())).unwrap();
+            assert!(child.wait().unwrap().success());
+            io.lines()
+                .find_map(|line| line.strip_prefix("syscw: "))
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+        };
+        let with_io_uring = write_syscalls(&["--no-hdd"]);
+        let without_io_uring = write_syscalls(&["--no-hdd", "--no-io-uring"]);
+        assert!(with_io_uring * 2 < without_io_uring);
+    }
+}
+
+#[test]
+// Paths this long can't be accessed directly, but can be by walking down from a directory one
+// component at a time.
+fn long_path() {
+    initialize();
+    let (source, destination) = (HYDRATED_DIR.join("long_path"), COPIES_DIR.join("long_path"));
+    remove(&source);
+    remove(&destination);
+    let component = OsStr::new(&"a".repeat(200)).to_owned();
+    let cwd = fs::Dir::cwd();
+    cwd.join(source.as_os_str()).create_dir(DIR_MODE).unwrap();
+    let mut dir = cwd.join(source.as_os_str()).open_dir().unwrap();
+    for _ in 0..25 {
+        dir.join(&component).create_dir(DIR_MODE).unwrap();
+        dir = dir.join(&component).open_dir().unwrap();
+    }
+    let mut file = dir.join(OsStr::new("file")).create(FILE_MODE).unwrap();
+    file.write_all(b"contents").unwrap();
+    assert!(dir.path().as_os_str().len() > 4096);
+
+    let result = fcp_run(&[&source, &destination]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let mut dir = cwd.join(destination.as_os_str()).open_dir().unwrap();
+    for _ in 0..25 {
+        dir = dir.join(&component).open_dir().unwrap();
+    }
+    let mut contents = String::new();
+    dir.join(OsStr::new("file"))
+        .open()
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "contents");
+}
+
+#[test]
+// Deep enough that recursing once per level would overflow the stack of a rayon worker thread.
+fn deeply_nested() {
+    initialize();
+    const DEPTH: usize = 10_000;
+    let (source, destination) = (
+        HYDRATED_DIR.join("deeply_nested"),
+        COPIES_DIR.join("deeply_nested"),
+    );
+    remove(&source);
+    remove(&destination);
+    let (cwd, name) = (fs::Dir::cwd(), OsStr::new("d"));
+    cwd.join(source.as_os_str()).create_dir(DIR_MODE).unwrap();
+    let mut dir = cwd.join(source.as_os_str()).open_dir().unwrap();
+    for _ in 0..DEPTH {
+        dir.join(name).create_dir(DIR_MODE).unwrap();
+        dir = dir.join(name).open_dir().unwrap();
+    }
+
+    let result = fcp_run(&[&source, &destination]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let mut dir = cwd.join(destination.as_os_str()).open_dir().unwrap();
+    for _ in 0..DEPTH {
+        dir = dir.join(name).open_dir().unwrap();
+    }
+    assert_eq!(dir.read().unwrap().count(), 0);
+}
+
+#[test]
+// Far more directories are copied at once than the lowered limit on open file descriptors allows
+// for.
+fn descriptor_exhaustion() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("descriptor_exhaustion"),
+        COPIES_DIR.join("descriptor_exhaustion"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for i in 0..64 {
+        let dir = source.join(i.to_string());
+        fs::create_dir(&dir, DIR_MODE).unwrap();
+        for j in 0..4 {
+            let mut file = fs::create(dir.join(j.to_string()), FILE_MODE).unwrap();
+            file.write_all(&[0; 4096]).unwrap();
+        }
+    }
+
+    let result = Command::new("sh")
+        .args(["-c", "ulimit -n 16 && exec \"$0\" \"$@\""])
+        .arg(fcp_executable_path())
+        .args(["-j", "32"])
+        .args([&source, &destination])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    let result = Command::new("diff")
+        .arg("-r")
+        .args([&source, &destination])
+        .status()
+        .unwrap();
+    assert!(result.success());
+}
+
+#[test]
+// Opening a FIFO for writing blocks until it is opened for reading, which stands in for a hung
+// filesystem here.
+fn op_timeout() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("op_timeout"),
+        COPIES_DIR.join("op_timeout"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    let sources = ["hung", "a", "b"].map(|name| source.join(name));
+    for source in &sources {
+        fs::create(source, FILE_MODE).unwrap();
+    }
+    fs::mkfifo(destination.join("hung"), Permissions::from_mode(FILE_MODE)).unwrap();
+
+    let mut args = vec![OsStr::new("--op-timeout=200ms"), OsStr::new("-j4")];
+    args.extend(sources.iter().map(|source| source.as_os_str()));
+    args.push(destination.as_os_str());
+    let result = fcp_run(&args);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        format!(
+            "{}: operation timed out after 200ms\n",
+            destination.join("hung").display()
+        )
+    );
+    for name in ["a", "b"] {
+        assert!(fs::symlink_metadata(destination.join(name)).is_ok());
+    }
+
+    // Waiting for the bandwidth limit (which takes a second here) isn't mistaken for the copy
+    // having stalled on the hung operation, so the throttled file is still copied in full.
+    fs::create(&sources[1], FILE_MODE)
+        .unwrap()
+        .write_all(&[1; 128 << 10])
+        .unwrap();
+    remove(&destination.join("a"));
+    let mut args = vec![
+        OsStr::new("--op-timeout=200ms"),
+        OsStr::new("-j4"),
+        OsStr::new("--bwlimit=64K"),
+    ];
+    args.extend(sources[..2].iter().map(|source| source.as_os_str()));
+    args.push(destination.as_os_str());
+    let result = fcp_run(&args);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        format!(
+            "{}: operation timed out after 200ms\n",
+            destination.join("hung").display()
+        )
+    );
+    assert_eq!(
+        fs::read_to_string(destination.join("a")).unwrap(),
+        "\u{1}".repeat(128 << 10)
+    );
+
+    // Reading a stream is watched as well, here a FIFO whose writer never writes anything.
+    let stalled = source.join("stalled");
+    fs::mkfifo(&stalled, Permissions::from_mode(FILE_MODE)).unwrap();
+    let _writer = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&stalled)
+        .unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--op-timeout=200ms"),
+        OsStr::new("--copy-contents"),
+        stalled.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        format!(
+            "{}, {}: operation timed out after 200ms\n",
+            stalled.display(),
+            destination.join("stalled").display()
+        )
+    );
+
+    let result = fcp_run(&["--op-timeout=5x", "source", "destination"]);
+    assert!(!result.success);
+    assert_eq!(result.stderr, "5x: invalid duration\n");
+}
+
+#[test]
+// `ENOENT` stands in for a transient error here, with the source being created while `fcp` waits
+// to retry looking it up.
+fn retries() {
+    initialize();
+    let (source, destination) = (HYDRATED_DIR.join("retries"), COPIES_DIR.join("retries"));
+    remove(&source);
+    remove(&destination);
+    // Copying into a directory which already exists, as otherwise looking up the destination would
+    // be retried too.
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    let child = Command::new(fcp_executable_path())
+        .args(["--verbose", "--retries=5", "--retry-errors=ENOENT"])
+        .args([&source, &destination])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(250));
+    fs::create(&source, FILE_MODE).unwrap();
+    let result = child.wait_with_output().unwrap();
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    assert!(stdout.starts_with(&format!("{}: succeeded after", source.display())));
+    assert!(fs::symlink_metadata(destination.join("retries"))
+        .unwrap()
+        .is_file());
+
+    let result = fcp_run(&["--retry-errors=EIO,EBOGUS", "source", "destination"]);
+    assert!(!result.success);
+    assert_eq!(result.stderr, "EBOGUS: unknown error name\n");
+}
+
+#[test]
+fn secure_dest() {
+    initialize();
+    let fixture_file = "secure_dest.json";
+    hydrate_fixture(fixture_file);
+    let source = HYDRATED_DIR.join("secure_dest");
+    let destination = COPIES_DIR.join("secure_dest");
+    let target = destination.join("target");
+    let symlink = destination.join("secure_dest");
+    let reset = || {
+        remove(&destination);
+        fs::create_dir(&destination, DIR_MODE).unwrap();
+        fs::create(&target, FILE_MODE).unwrap();
+        fs::symlink("target", &symlink).unwrap();
+    };
+
+    // Without `--secure-dest` the planted symlink is followed, overwriting its target.
+    reset();
+    let result = fcp_run(&[&source, &destination]);
+    assert!(result.success);
+    assert_eq!(fs::metadata(&target).unwrap().len(), 1024);
+
+    for dest in [&destination, &symlink] {
+        reset();
+        let result = fcp_run(&[
+            source.as_os_str(),
+            OsStr::new("--secure-dest"),
+            dest.as_os_str(),
+        ]);
+        assert!(!result.success);
+        assert!(result.stderr.contains(symlink.to_str().unwrap()));
+        assert_eq!(fs::metadata(&target).unwrap().len(), 0);
+    }
+}
+
+#[test]
+fn uncached() {
+    initialize();
+    let fixture_file = "uncached.json";
+    hydrate_fixture(fixture_file);
+    for options in [
+        &["--nocache"][..],
+        &["--direct"],
+        &["--direct", "--nocache", "--no-hdd", "--chunk-size=1M"],
+    ] {
+        assert_copy_succeeds(fixture_file, options);
+        // This is checked before diffing the copy, which reads it back into the page cache.
+        assert_eq!(cached_pages(&COPIES_DIR.join("uncached/odd_size")), 0);
+        assert!(diff(fixture_file).success());
+    }
+}
+
+/// The number of pages of the file at `path` which are resident in the page cache.
+fn cached_pages(path: &Path) -> usize {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path).unwrap();
+    let len = file.metadata().unwrap().len() as usize;
+    // SAFETY: `sysconf` has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut resident = vec![0_u8; len.div_ceil(page_size)];
+    // SAFETY: The mapping is only passed to `mincore`, which doesn't touch its contents, and is
+    // unmapped before returning. `resident` has room for a byte per page of the mapping.
+    unsafe {
+        let mapping = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        );
+        assert_ne!(mapping, libc::MAP_FAILED);
+        let result = libc::mincore(mapping, len, resident.as_mut_ptr());
+        libc::munmap(mapping, len);
+        assert_eq!(result, 0);
+    }
+    resident.iter().filter(|&&page| page & 1 != 0).count()
+}
+
 #[test]
 fn socket() {
     initialize();
@@ -105,6 +674,25 @@ fn socket() {
     let result = copy_fixture(fixture_file);
     assert!(!result.success);
     assert!(result.stderr.contains("sockets cannot be copied"));
+
+    let destination = COPIES_DIR.join(fixture_file.strip_suffix(".json").unwrap());
+    let result = copy_fixture_with_options(fixture_file, &["--special=skip"]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(fs::symlink_metadata(&destination).is_err());
+
+    let result = copy_fixture_with_options(fixture_file, &["--special=recreate"]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let file_type = fs::file_type(&destination).unwrap();
+    assert!(matches!(file_type, fs::FileType::Socket));
+
+    let result = copy_fixture_with_options(fixture_file, &["--special=bogus"]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        "bogus: expected one of error, skip, or recreate\n"
+    );
 }
 
 #[test]
@@ -119,6 +707,129 @@ fn fifo() {
     assert!(matches!(file_type, fs::FileType::Fifo))
 }
 
+#[test]
+fn standard_streams() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("standard_streams"),
+        COPIES_DIR.join("standard_streams"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    let run = |args: &[&OsStr], input: &[u8]| {
+        let mut child = Command::new(fcp_executable_path())
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+        output.stdout
+    };
+
+    let file = destination.join("stdin");
+    run(&[OsStr::new("-"), file.as_os_str()], b"from stdin");
+    assert_eq!(std::fs::read(&file).unwrap(), b"from stdin");
+    let stdout = run(&[file.as_os_str(), OsStr::new("-")], b"");
+    assert_eq!(stdout, b"from stdin");
+
+    let file = destination.join("proc");
+    let args = [
+        OsStr::new("--copy-contents"),
+        OsStr::new("/proc/self/fd/0"),
+        file.as_os_str(),
+    ];
+    run(&args, b"through a symlink");
+    assert_eq!(std::fs::read(&file).unwrap(), b"through a symlink");
+
+    let fifo = source.join("fifo");
+    let status = Command::new("mkfifo").arg(&fifo).status().unwrap();
+    assert!(status.success());
+    let writer = {
+        let fifo = fifo.clone();
+        thread::spawn(move || std::fs::write(fifo, b"from a fifo").unwrap())
+    };
+    let file = destination.join("fifo");
+    run(
+        &[
+            OsStr::new("--copy-contents"),
+            fifo.as_os_str(),
+            file.as_os_str(),
+        ],
+        b"",
+    );
+    writer.join().unwrap();
+    assert!(fs::symlink_metadata(&file).unwrap().is_file());
+    assert_eq!(std::fs::read(&file).unwrap(), b"from a fifo");
+}
+
+#[test]
+fn pseudo_files() {
+    initialize();
+    let destination = COPIES_DIR.join("pseudo_files");
+    remove(&destination);
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    // Files in procfs report a size of zero, despite having contents. Chunking would otherwise
+    // copy them according to their reported size.
+    for options in [&[][..], &["--chunk-size=1"]] {
+        let file = destination.join("version");
+        let mut args = options.iter().map(OsStr::new).collect::<Vec<_>>();
+        args.extend([OsStr::new("/proc/version"), file.as_os_str()]);
+        let result = fcp_run(&args);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert_eq!(
+            std::fs::read(&file).unwrap(),
+            std::fs::read("/proc/version").unwrap()
+        );
+    }
+
+    // Attributes in sysfs which can only be written fail to be read with EACCES, which
+    // `--special=skip` skips. Every driver has some, such as `bind` and `uevent`.
+    let driver = std::fs::read_dir("/sys/bus")
+        .into_iter()
+        .flatten()
+        .flat_map(|bus| std::fs::read_dir(bus.unwrap().path().join("drivers")))
+        .flatten()
+        .map(|driver| driver.unwrap().path())
+        .next();
+    let driver = match driver {
+        Some(driver) => driver,
+        None => return,
+    };
+    let write_only = std::fs::read_dir(&driver)
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|entry| {
+            let metadata = entry.metadata().unwrap();
+            metadata.is_file() && metadata.permissions().mode() & 0o444 == 0
+        })
+        .map(|entry| entry.file_name())
+        .collect::<Vec<_>>();
+    assert!(!write_only.is_empty());
+    let copy = destination.join("driver");
+    let result = fcp_run(&[&driver, &copy]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("Permission denied"));
+    remove(&copy);
+    let result = fcp_run(&[
+        OsStr::new("--special=skip"),
+        driver.as_os_str(),
+        copy.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for name in write_only {
+        assert!(!copy.join(name).exists());
+    }
+}
+
 #[test]
 fn character_device() {
     initialize();
@@ -145,6 +856,531 @@ fn character_device() {
     );
 }
 
+#[test]
+fn device_nodes() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("device_nodes"),
+        COPIES_DIR.join("device_nodes"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    // The same device as `/dev/null`, which requires the CAP_MKNOD capability to create.
+    let status = Command::new("mknod")
+        .arg(source.join("null"))
+        .args(["c", "1", "3"])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    if !status.success() {
+        return;
+    }
+
+    let result = fcp_run(&[&source, &destination]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let (source_metadata, dest_metadata) = (
+        fs::symlink_metadata(source.join("null")).unwrap(),
+        fs::symlink_metadata(destination.join("null")).unwrap(),
+    );
+    assert!(dest_metadata.file_type().is_char_device());
+    assert_eq!(dest_metadata.rdev(), source_metadata.rdev());
+
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--copy-contents"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let dest_metadata = fs::symlink_metadata(destination.join("null")).unwrap();
+    assert!(dest_metadata.is_file());
+    assert_eq!(dest_metadata.len(), 0);
+
+    // A device given directly as an argument has its contents copied regardless.
+    remove(&destination);
+    let result = fcp_run(&[&source.join("null"), &destination]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let dest_metadata = fs::symlink_metadata(&destination).unwrap();
+    assert!(dest_metadata.is_file());
+    assert_eq!(dest_metadata.len(), 0);
+}
+
+#[test]
+fn argument_parsing() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("argument_parsing"),
+        COPIES_DIR.join("argument_parsing"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    for name in ["-h", "a", "b"] {
+        fs::create(source.join(name), FILE_MODE).unwrap();
+    }
+    let destination = absolute(&destination);
+    let run = |args: &[&str]| {
+        Command::new(fcp_executable_path())
+            .args(args)
+            .current_dir(&source)
+            .output()
+            .unwrap()
+    };
+
+    let result = run(&[
+        "-vj2",
+        "-t",
+        destination.to_str().unwrap(),
+        "a",
+        "--",
+        "-h",
+        "b",
+    ]);
+    assert!(result.status.success());
+    for name in ["-h", "a", "b"] {
+        assert!(fs::symlink_metadata(destination.join(name)).is_ok());
+    }
+
+    // With `-T` the destination is copied to as a file, even though it's a directory.
+    let result = run(&["-T", "a", destination.to_str().unwrap()]);
+    assert!(!result.status.success());
+    assert!(fs::symlink_metadata(destination.join("a")).is_ok());
+
+    for (args, error) in [
+        (&["--bogus", "a", "b"][..], "--bogus: unknown option"),
+        (&["-vx", "a", "b"], "-x: unknown option"),
+        (
+            &["--hdd=yes", "a", "b"],
+            "--hdd: option does not take a value",
+        ),
+        (&["a", "b", "-t"], "-t: missing value"),
+        (&["--split=4X", "a", "b"], "4X: invalid size"),
+        (&["-t", "b"], "Please provide at least one SOURCE"),
+        (&["-T", "-t", "b", "a"], "cannot combine"),
+        (
+            &["-T", "a", "b", "c"],
+            "Please provide exactly two arguments",
+        ),
+    ] {
+        let result = run(args);
+        assert!(!result.status.success());
+        assert!(String::from_utf8(result.stderr).unwrap().starts_with(error));
+    }
+}
+
+#[test]
+// The names here are Latin-1, as found in legacy archives, and so aren't valid UTF-8.
+fn non_utf8_paths() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("non_utf8_paths"),
+        COPIES_DIR.join("non_utf8_paths"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    let (name, dest_dir) = (
+        OsStr::from_bytes(b"caf\xe9"),
+        destination.join(OsStr::from_bytes(b"d\xe9j\xe0")),
+    );
+    fs::create_dir(&dest_dir, DIR_MODE).unwrap();
+    let mut file = fs::create(source.join(name), FILE_MODE).unwrap();
+    file.write_all(b"contents").unwrap();
+
+    let result = fcp_run(&[
+        OsStr::new("-t"),
+        dest_dir.as_os_str(),
+        source.join(name).as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(std::fs::read(dest_dir.join(name)).unwrap(), b"contents");
+
+    // Bytes which aren't valid UTF-8 are escaped in error messages rather than being replaced.
+    let missing = source.join(OsStr::from_bytes(b"missing\xe9"));
+    let result = fcp_run(&[missing.as_os_str(), destination.as_os_str()]);
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .starts_with(&format!("{}/missing\\xE9: ", source.display())));
+    let result = fcp_run(&[
+        OsStr::from_bytes(b"--jobs=\xff"),
+        OsStr::new("a"),
+        OsStr::new("b"),
+    ]);
+    assert!(!result.success);
+    assert_eq!(result.stderr, "\\xFF: invalid value\n");
+}
+
+#[test]
+fn files_from() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("files_from"),
+        COPIES_DIR.join("files_from"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("a/b"), DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    for name in ["a/b/x", "a/y", "z", "unlisted"] {
+        fs::create(source.join(name), FILE_MODE).unwrap();
+    }
+    std::fs::set_permissions(source.join("a/b"), Permissions::from_mode(0o700)).unwrap();
+    let destination = absolute(&destination);
+    let run = |args: &[&OsStr], list: &[u8]| {
+        let mut child = Command::new(fcp_executable_path())
+            .args(args)
+            .current_dir(&source)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(list).unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let args = [
+        OsStr::new("-0"),
+        OsStr::new("--files-from=-"),
+        destination.as_os_str(),
+    ];
+    let result = run(&args, b"./a/b/x\0a/y\0z\0");
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    for name in ["a/b/x", "a/y", "z"] {
+        assert!(fs::symlink_metadata(destination.join(name))
+            .unwrap()
+            .is_file());
+    }
+    assert!(fs::symlink_metadata(destination.join("unlisted")).is_err());
+    let permissions = fs::symlink_metadata(destination.join("a/b"))
+        .unwrap()
+        .permissions();
+    assert_eq!(permissions.mode() & 0o777, 0o700);
+
+    let args = [OsStr::new("--files-from=-"), destination.as_os_str()];
+    let result = run(&args, b"z\n./z\n../z\n");
+    assert!(!result.status.success());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("./z: path is listed more than once"));
+    assert!(stderr.contains("../z: paths containing '..' cannot be recreated"));
+}
+
+#[test]
+fn parents() {
+    initialize();
+    let (source, destination) = (HYDRATED_DIR.join("parents"), COPIES_DIR.join("parents"));
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("a/x"), DIR_MODE).unwrap();
+    fs::create_dir(source.join("b"), DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    for name in ["a/x/config.toml", "b/config.toml"] {
+        fs::create(source.join(name), FILE_MODE).unwrap();
+    }
+    std::fs::set_permissions(source.join("a/x"), Permissions::from_mode(0o750)).unwrap();
+    let destination = absolute(&destination);
+    let run = |args: &[&OsStr]| {
+        Command::new(fcp_executable_path())
+            .arg("--parents")
+            .args(args)
+            .current_dir(&source)
+            .output()
+            .unwrap()
+    };
+
+    // Without `--parents` these sources would conflict, as they have the same file name.
+    let args = [
+        OsStr::new("a/x/config.toml"),
+        OsStr::new("./b/config.toml"),
+        destination.as_os_str(),
+    ];
+    let result = run(&args);
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    for name in ["a/x/config.toml", "b/config.toml"] {
+        assert!(fs::symlink_metadata(destination.join(name))
+            .unwrap()
+            .is_file());
+    }
+    let permissions = fs::symlink_metadata(destination.join("a/x"))
+        .unwrap()
+        .permissions();
+    assert_eq!(permissions.mode() & 0o777, 0o750);
+
+    let args = [OsStr::new("b"), OsStr::new("./b"), destination.as_os_str()];
+    let result = run(&args);
+    assert!(!result.status.success());
+    assert!(String::from_utf8(result.stderr)
+        .unwrap()
+        .contains("paths have the same relative path"));
+}
+
+#[test]
+fn mkdir() {
+    initialize();
+    let (source, destination) = (HYDRATED_DIR.join("mkdir"), COPIES_DIR.join("mkdir"));
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE).unwrap();
+
+    let dest_file = destination.join("a/b/file");
+    let result = fcp_run(&[OsStr::new("-D"), source.as_os_str(), dest_file.as_os_str()]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(fs::symlink_metadata(&dest_file).unwrap().is_file());
+
+    let dest_dir = destination.join("c/d");
+    let result = fcp_run(&[
+        OsStr::new("--mkdir"),
+        OsStr::new("--mkdir-mode=700"),
+        OsStr::new("-t"),
+        dest_dir.as_os_str(),
+        source.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(fs::symlink_metadata(dest_dir.join("mkdir"))
+        .unwrap()
+        .is_file());
+    let permissions = fs::symlink_metadata(&dest_dir).unwrap().permissions();
+    assert_eq!(permissions.mode() & 0o777, 0o700);
+
+    let result = fcp_run(&["--mkdir-mode=9", "source", "destination"]);
+    assert!(!result.success);
+    assert_eq!(result.stderr, "9: invalid mode\n");
+}
+
+#[test]
+fn sanitize_names() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("sanitize_names"),
+        COPIES_DIR.join("sanitize_names"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("a?b"), DIR_MODE).unwrap();
+    for name in ["a?b/c:d", "CON.txt", "trailing."] {
+        fs::create(source.join(name), FILE_MODE).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--sanitize-names=always"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for name in ["a_b/c_d", "CON_.txt", "trailing_"] {
+        assert!(fs::symlink_metadata(destination.join(name))
+            .unwrap()
+            .is_file());
+    }
+
+    remove(&destination);
+    fs::create(source.join("con.TXT"), FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--sanitize-names=always"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .contains("as the destination filesystem doesn't distinguish their names"));
+
+    remove(&destination);
+    let result = fcp_run(&[source.as_os_str(), destination.as_os_str()]);
+    assert!(result.success);
+    assert!(fs::symlink_metadata(destination.join("a?b/c:d")).is_ok());
+}
+
+#[test]
+fn name_collisions() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("name_collisions"),
+        COPIES_DIR.join("name_collisions"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    // The same name precomposed (NFC) and decomposed (NFD), as well as differing in case.
+    for name in ["caf\u{e9}", "cafe\u{301}", "notes.txt", "NOTES.txt"] {
+        fs::create(source.join(name), FILE_MODE).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--case-insensitive"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result
+            .stderr
+            .matches("as the destination filesystem doesn't distinguish their names")
+            .count(),
+        2
+    );
+
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--case-insensitive"),
+        OsStr::new("--name-collisions=rename"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let names = std::fs::read_dir(&destination)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names.len(), 4);
+    assert!(names.iter().any(|name| name.ends_with(" (1)")));
+    assert!(names.iter().any(|name| name.ends_with(" (1).txt")));
+
+    let result = fcp_run(&[
+        OsStr::new("--case-insensitive"),
+        source.join("notes.txt").as_os_str(),
+        source.join("NOTES.txt").as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .contains("paths have the same file name on the destination filesystem"));
+}
+
+#[test]
+fn name_collisions_case_folding() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("name_collisions_case_folding"),
+        COPIES_DIR.join("name_collisions_case_folding"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    // These are the same under full case folding, whereas names which aren't valid UTF-8 (and so
+    // can't be folded) are compared exactly.
+    let names: [&[u8]; 4] = ["stra\u{df}e".as_bytes(), b"STRASSE", b"a\xff", b"a\xfe"];
+    for name in names {
+        fs::create(source.join(OsStr::from_bytes(name)), FILE_MODE).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--case-insensitive"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result
+            .stderr
+            .matches("as the destination filesystem doesn't distinguish their names")
+            .count(),
+        1
+    );
+    assert_eq!(std::fs::read_dir(&destination).unwrap().count(), 3);
+}
+
+#[test]
+fn name_collisions_deterministic() {
+    initialize();
+    let (source, destination) = (
+        HYDRATED_DIR.join("name_collisions_deterministic"),
+        COPIES_DIR.join("name_collisions_deterministic"),
+    );
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    // Plenty of other files, so that the directory is copied in several batches at once.
+    for i in 0..1000 {
+        fs::create(source.join(format!("{}", i)), FILE_MODE).unwrap();
+    }
+    for name in ["README", "readme"] {
+        std::fs::write(source.join(name), name).unwrap();
+    }
+    // Whichever entry is copied first, it's always the later of the names which is renamed.
+    for _ in 0..10 {
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new("-j4"),
+            OsStr::new("--case-insensitive"),
+            OsStr::new("--name-collisions=rename"),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(
+            std::fs::read(destination.join("README")).unwrap(),
+            b"README"
+        );
+        assert_eq!(
+            std::fs::read(destination.join("readme (1)")).unwrap(),
+            b"readme"
+        );
+    }
+}
+
+#[test]
+fn split() {
+    initialize();
+    let (source, destination) = (HYDRATED_DIR.join("split"), COPIES_DIR.join("split"));
+    remove(&source);
+    remove(&destination);
+    let contents = (0..2500).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(&source, &contents).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    // The limit on the size of files imposed by the destination filesystem is stood in for by
+    // `FCP_MAX_FILE_SIZE`, as one which has such a limit can't be relied upon to be available.
+    let fcp_split = |options: &[&str]| {
+        let output = Command::new(fcp_executable_path())
+            .env("FCP_MAX_FILE_SIZE", "1000")
+            .args(options)
+            .args([&source, &destination])
+            .output()
+            .unwrap();
+        CommandResult {
+            stderr: String::from_utf8(output.stderr).unwrap(),
+            success: output.status.success(),
+        }
+    };
+
+    let result = fcp_split(&[]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("allows at most 1000 bytes per file"));
+    assert!(!destination.join("split").exists());
+    let result = fcp_split(&["--split=2000"]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("the size given by --split exceeds"));
+    assert!(!destination.join("split").exists());
+
+    let result = fcp_split(&["--split=1000"]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let mut parts = Vec::new();
+    for (name, len) in [("split.000", 1000), ("split.001", 1000), ("split.002", 500)] {
+        let part = std::fs::read(destination.join(name)).unwrap();
+        assert_eq!(part.len(), len);
+        parts.extend(part);
+    }
+    assert_eq!(parts, contents);
+    assert_eq!(
+        std::fs::read_to_string(destination.join("split.manifest")).unwrap(),
+        "# The parts of 'split' (2500 bytes), to be concatenated in order to reassemble it.\n\
+         1000 split.000\n1000 split.001\n500 split.002\n"
+    );
+    assert!(!destination.join("split").exists());
+}
+
 #[test]
 fn too_few_arguments() {
     initialize();
@@ -342,12 +1578,23 @@ fn prevent_copying_into_self() {
     ]);
     // Copying into self by way of purely relative paths
     assert_self_copy_failure(&[Path::new(".."), Path::new(".")]);
+    // Copying into a destination within self which doesn't exist yet, which is rejected before
+    // anything is created
+    let nested = source.join("nested").join("deeper");
+    assert_self_copy_failure(&[&source, &nested]);
+    assert!(fs::symlink_metadata(source.join("nested")).is_err());
     let normal = source.join("normal");
     fs::create(&normal, FILE_MODE).unwrap();
     // Copying a non-directory onto itself
     let result = fcp_run(&[&normal, &normal]);
     assert!(!result.success);
     assert!(result.stderr.contains("Cannot overwrite file"));
+    // Copying a non-directory onto a symlink to itself
+    let normal_link = source.join("normal_link");
+    fs::symlink("normal", &normal_link).unwrap();
+    let result = fcp_run(&[&normal, &normal_link]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("Cannot overwrite file"));
 }
 
 #[test]